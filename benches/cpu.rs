@@ -0,0 +1,112 @@
+//! Emulator throughput. Each benchmark reports its throughput in emulated
+//! cycles per second, so `Melem/s` reads as emulated MHz.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use emulator::cpm::{CpmExit, CpmMachine};
+use emulator::interrupt::RstInterrupts;
+use emulator::memory::MirroredMemory;
+use emulator::{DefaultHandler, Emu8080, InOutHandler};
+
+static DIAG_BYTES: &[u8] = include_bytes!("../tests/cpudiag.bin");
+static INVADERS_ROM: &[u8] = include_bytes!("../src/invaders.rom");
+
+/// Cycles between Space Invaders' mid screen and VBLANK interrupts.
+const HALF_FRAME_CYCLES: usize = 16_667;
+const FRAMES: usize = 60;
+
+fn run_diag() -> u64 {
+    let mut machine = CpmMachine::new(DIAG_BYTES);
+    machine.echo = false;
+    assert_eq!(machine.run(u64::MAX).unwrap(), CpmExit::WarmBoot);
+    machine.cycles()
+}
+
+fn alu_loop() -> u64 {
+    let mut emu = Emu8080::new(DefaultHandler);
+    emu.load_at(
+        0,
+        &[
+            0x01, 0x00, 0x00, // LXI B,0
+            0x80, // loop: ADD B
+            0x99, // SBB C
+            0xa1, // ANA C
+            0xaa, // XRA D
+            0xb3, // ORA E
+            0xbc, // CMP H
+            0x27, // DAA
+            0x07, // RLC
+            0x0b, // DCX B
+            0x78, // MOV A,B
+            0xb1, // ORA C
+            0xc2, 0x03, 0x00, // JNZ loop
+            0x76, // HLT
+        ],
+    );
+    let mut cycles = 0;
+    while !emu.is_halted() {
+        cycles += emu.step() as u64;
+    }
+    cycles
+}
+
+/// Space Invaders' inputs and shift register, without a display.
+#[derive(Default)]
+struct HeadlessInvaders {
+    offset: u8,
+    xy: u16,
+}
+
+impl InOutHandler for HeadlessInvaders {
+    fn read(&mut self, port: u8, _cycles: u64) -> u8 {
+        match port {
+            0 => 14,
+            3 => ((self.xy >> (8 - self.offset)) & 0xff) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u8, val: u8, _cycles: u64) {
+        match port {
+            2 => self.offset = val & 0x7,
+            4 => self.xy = (self.xy >> 8) | (u16::from(val) << 8),
+            _ => {}
+        }
+    }
+}
+
+fn invaders_frames(frames: usize) -> u64 {
+    let mut emu = Emu8080::with_interrupts(
+        HeadlessInvaders::default(),
+        MirroredMemory::new(0x4000),
+        RstInterrupts::default(),
+    );
+    emu.load_at(0, INVADERS_ROM);
+    let mut total = 0;
+    for _ in 0..frames {
+        for &interrupt in &[1, 2] {
+            let mut cycles = 0;
+            while cycles < HALF_FRAME_CYCLES {
+                cycles += emu.step();
+            }
+            emu.interrupts.request(interrupt);
+            total += cycles as u64;
+        }
+    }
+    total
+}
+
+fn bench(c: &mut Criterion, name: &str, workload: fn() -> u64) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(workload()));
+    group.bench_function("step", |b| b.iter(workload));
+    group.finish();
+}
+
+fn cpu_benches(c: &mut Criterion) {
+    bench(c, "cpudiag", run_diag);
+    bench(c, "alu_loop", alu_loop);
+    bench(c, "invaders_60_frames", || invaders_frames(FRAMES));
+}
+
+criterion_group!(benches, cpu_benches);
+criterion_main!(benches);
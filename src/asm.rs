@@ -0,0 +1,839 @@
+//! A two-pass assembler for 8080 source in Intel syntax.
+//!
+//! Each line holds an optional label, an instruction or directive and an
+//! optional `;` comment. Labels end with `:`, which may be left out when
+//! they start the line. Labels starting with `.` are local to the previous
+//! label: `.loop` after `Draw:` is `Draw.loop`.
+//!
+//! The directives are `ORG`, `EQU` (or `=`), `DB`, `DW`, `DS` and `END`.
+//! Operands are expressions of numbers, symbols, `'c'` characters and `$`,
+//! the address of the line, with C operators and `HIGH` and `LOW`. Numbers
+//! are decimal, or hexadecimal written `0FFh`, `$FF` or `0xFF`, binary
+//! with a `b` suffix or octal with an `o` or `q` suffix.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::dis::{ALU, ALU_IMMEDIATE, CALLS, JUMPS, RETURNS};
+use crate::error::AsmError;
+use crate::symbols::Symbols;
+
+/// Instructions without operands.
+const IMPLIED: [(&str, u8); 17] = [
+    ("NOP", 0x00),
+    ("RLC", 0x07),
+    ("RRC", 0x0f),
+    ("RAL", 0x17),
+    ("RAR", 0x1f),
+    ("DAA", 0x27),
+    ("CMA", 0x2f),
+    ("STC", 0x37),
+    ("CMC", 0x3f),
+    ("HLT", 0x76),
+    ("RET", 0xc9),
+    ("XTHL", 0xe3),
+    ("PCHL", 0xe9),
+    ("XCHG", 0xeb),
+    ("DI", 0xf3),
+    ("SPHL", 0xf9),
+    ("EI", 0xfb),
+];
+
+/// Instructions taking a 16 bit address.
+const ADDRESSED: [(&str, u8); 6] = [
+    ("SHLD", 0x22),
+    ("LHLD", 0x2a),
+    ("STA", 0x32),
+    ("LDA", 0x3a),
+    ("JMP", 0xc3),
+    ("CALL", 0xcd),
+];
+
+/// Instructions taking a register pair.
+const PAIRED: [(&str, u8); 8] = [
+    ("LXI", 0x01),
+    ("STAX", 0x02),
+    ("INX", 0x03),
+    ("DAD", 0x09),
+    ("LDAX", 0x0a),
+    ("DCX", 0x0b),
+    ("POP", 0xc1),
+    ("PUSH", 0xc5),
+];
+
+const OTHERS: [&str; 11] = [
+    "MOV", "MVI", "INR", "DCR", "IN", "OUT", "RST", "ORG", "DB", "DW", "DS",
+];
+
+const DIRECTIVES: [&str; 3] = ["END", "EQU", "="];
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+
+/// The output of `assemble`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    /// Runs of consecutive bytes and their start address.
+    segments: Vec<(u16, Vec<u8>)>,
+    symbols: BTreeMap<String, i64>,
+}
+
+impl Program {
+    /// Runs of consecutive bytes emitted, and the address of each run, in
+    /// source order.
+    pub fn segments(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.segments
+            .iter()
+            .map(|(addr, bytes)| (*addr, bytes.as_slice()))
+    }
+
+    /// Lowest address a byte was emitted at.
+    pub fn origin(&self) -> u16 {
+        self.segments
+            .iter()
+            .map(|&(addr, _)| addr)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// The bytes from `origin` to the last byte emitted. Gaps left by ORG
+    /// and DS are filled with zeroes.
+    pub fn binary(&self) -> Vec<u8> {
+        let origin = usize::from(self.origin());
+        let end = self
+            .segments
+            .iter()
+            .map(|(addr, bytes)| usize::from(*addr) + bytes.len())
+            .max()
+            .unwrap_or(origin);
+        let mut image = vec![0; end - origin];
+        for (addr, bytes) in &self.segments {
+            let start = usize::from(*addr) - origin;
+            image[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        image
+    }
+
+    /// The program in Intel HEX format, with 16 bytes per data record.
+    pub fn intel_hex(&self) -> String {
+        let mut out = String::new();
+        for (addr, bytes) in &self.segments {
+            for (i, chunk) in bytes.chunks(16).enumerate() {
+                hex_record(&mut out, addr + (i * 16) as u16, 0x00, chunk);
+            }
+        }
+        hex_record(&mut out, 0, 0x01, &[]);
+        out
+    }
+
+    /// The value of a label or EQU, truncated to 16 bits.
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).map(|&val| val as u16)
+    }
+
+    /// Labels and EQUs as a symbol table, for traces.
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols = Symbols::new();
+        for (name, &val) in &self.symbols {
+            symbols.insert(val as u16, name);
+        }
+        symbols
+    }
+}
+
+fn hex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let [high, low] = addr.to_be_bytes();
+    let mut sum = (data.len() as u8)
+        .wrapping_add(high)
+        .wrapping_add(low)
+        .wrapping_add(kind);
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, kind).unwrap();
+    for &b in data {
+        sum = sum.wrapping_add(b);
+        write!(out, "{:02X}", b).unwrap();
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg()).unwrap();
+}
+
+/// Assembles `source`.
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut asm = Assembler::default();
+    // Labels are collected by the first pass, so that the second can
+    // resolve forward references
+    for pass in 0..2 {
+        asm.final_pass = pass == 1;
+        asm.addr = 0;
+        asm.scope.clear();
+        asm.defined.clear();
+        for (i, line) in source.lines().enumerate() {
+            match asm.line(line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(message) => {
+                    return Err(AsmError {
+                        line: i + 1,
+                        message,
+                    })
+                }
+            }
+        }
+    }
+    Ok(asm.program)
+}
+
+/// Whether `word` is an instruction or directive, and not a label.
+fn is_keyword(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    let word = word.as_str();
+    IMPLIED.iter().any(|&(name, _)| name == word)
+        || ADDRESSED.iter().any(|&(name, _)| name == word)
+        || PAIRED.iter().any(|&(name, _)| name == word)
+        || [&ALU, &ALU_IMMEDIATE, &JUMPS, &CALLS, &RETURNS]
+            .iter()
+            .any(|table| table.contains(&word))
+        || OTHERS.contains(&word)
+        || DIRECTIVES.contains(&word)
+}
+
+fn is_identifier_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || b"_.?@".contains(&c)
+}
+
+fn is_identifier(c: u8) -> bool {
+    is_identifier_start(c) || c.is_ascii_digit()
+}
+
+/// Removes the comment from `line`, ignoring `;` in quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits `operands` at commas outside of quotes and parentheses.
+fn split_operands(operands: &str) -> Vec<&str> {
+    if operands.is_empty() {
+        return vec![];
+    }
+    let (mut parts, mut start, mut depth, mut quote) = (vec![], 0, 0, None);
+    for (i, c) in operands.char_indices() {
+        match (quote, c) {
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(operands[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(operands[start..].trim());
+    parts
+}
+
+/// Parses a number token, in any of the supported bases.
+fn number(token: &str) -> Result<i64, String> {
+    let lower = token.to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_suffix('h') {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_suffix('b') {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_suffix(|c| c == 'o' || c == 'q') {
+        (digits, 8)
+    } else if let Some(digits) = lower.strip_suffix('d') {
+        (digits, 10)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("Invalid number {}", token))
+}
+
+/// A string operand of DB, quoted with `'` or `"`. A doubled quote stands
+/// for itself.
+fn string(operand: &str) -> Option<Vec<u8>> {
+    let quote = operand.chars().next().filter(|&c| c == '\'' || c == '"')?;
+    let inner = operand.strip_prefix(quote)?.strip_suffix(quote)?;
+    let doubled: String = [quote, quote].iter().collect();
+    Some(inner.replace(&doubled, &quote.to_string()).into_bytes())
+}
+
+#[derive(Default)]
+struct Assembler {
+    program: Program,
+    /// Set for the second pass, where every symbol must be defined.
+    final_pass: bool,
+    /// Address of the next byte, which may run one past the end of memory.
+    addr: usize,
+    /// Address of the current line, `$` in expressions.
+    start: usize,
+    /// The last label not starting with `.`, which local labels belong to.
+    scope: String,
+    /// Symbols defined so far in the current pass, resolved or not.
+    defined: BTreeSet<String>,
+}
+
+impl Assembler {
+    /// Assembles one line. Returns false on END.
+    fn line(&mut self, line: &str) -> Result<bool, String> {
+        let line = strip_comment(line);
+        self.start = self.addr;
+        let starts_line = !line.starts_with(char::is_whitespace);
+        let mut rest = line.trim();
+        let mut label = None;
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        if let Some(colon) = rest[..word_end].find(':') {
+            label = Some(&rest[..colon]);
+            rest = rest[colon + 1..].trim_start();
+        } else if starts_line && !rest.is_empty() && !is_keyword(&rest[..word_end]) {
+            label = Some(&rest[..word_end]);
+            rest = rest[word_end..].trim_start();
+        }
+        let mnemonic_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let mnemonic = rest[..mnemonic_end].to_ascii_uppercase();
+        let operands = split_operands(rest[mnemonic_end..].trim());
+
+        if mnemonic == "EQU" || mnemonic == "=" {
+            let name = label.ok_or("EQU without a name")?;
+            let name = self.qualify(name)?;
+            self.declare(&name)?;
+            if let Some(val) = self.value(&operands, 1, 0)? {
+                self.program.symbols.insert(name, val);
+            }
+            return Ok(true);
+        }
+        if let Some(label) = label {
+            let name = self.qualify(label)?;
+            if !label.starts_with('.') {
+                self.scope = name.clone();
+            }
+            self.define(name, self.addr as i64)?;
+        }
+        match mnemonic.as_str() {
+            "" => {}
+            "END" => return Ok(false),
+            "ORG" => {
+                let addr = self.known(&operands)?;
+                if !(0..=0xFFFF).contains(&addr) {
+                    return Err(format!("Address out of range: {}", addr));
+                }
+                self.addr = addr as usize;
+            }
+            "DS" => {
+                let len = self.known(&operands)?;
+                if len < 0 || self.addr + len as usize > 0x10000 {
+                    return Err("Reserved space out of memory".to_string());
+                }
+                self.addr += len as usize;
+            }
+            "DB" => {
+                for operand in &operands {
+                    match string(operand) {
+                        Some(bytes) if bytes.len() != 1 => self.emit(&bytes)?,
+                        _ => {
+                            let val = self.byte(operand)?;
+                            self.emit(&[val])?;
+                        }
+                    }
+                }
+            }
+            "DW" => {
+                for operand in &operands {
+                    let val = self.word(operand)?;
+                    self.emit(&val.to_le_bytes())?;
+                }
+            }
+            _ => {
+                let bytes = self.instruction(&mnemonic, &operands)?;
+                self.emit(&bytes)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// The full name of `label`, prefixed with the scope for local labels.
+    fn qualify(&self, label: &str) -> Result<String, String> {
+        let bytes = label.as_bytes();
+        if bytes.is_empty()
+            || !is_identifier_start(bytes[0])
+            || !bytes.iter().all(|&c| is_identifier(c))
+        {
+            return Err(format!("Invalid label {}", label));
+        }
+        if label.starts_with('.') {
+            Ok(format!("{}{}", self.scope, label))
+        } else {
+            Ok(label.to_string())
+        }
+    }
+
+    fn define(&mut self, name: String, val: i64) -> Result<(), String> {
+        self.declare(&name)?;
+        self.program.symbols.insert(name, val);
+        Ok(())
+    }
+
+    /// Fails if `name` was already defined in this pass, even by an EQU of
+    /// a value not known yet.
+    fn declare(&mut self, name: &str) -> Result<(), String> {
+        if !self.defined.insert(name.to_string()) {
+            return Err(format!("Duplicate symbol {}", name));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.addr + bytes.len() > 0x10000 {
+            return Err("Code out of memory".to_string());
+        }
+        if self.final_pass {
+            let addr = self.addr;
+            match self.program.segments.last_mut() {
+                Some((start, segment)) if usize::from(*start) + segment.len() == addr => {
+                    segment.extend_from_slice(bytes)
+                }
+                _ => self.program.segments.push((addr as u16, bytes.to_vec())),
+            }
+        }
+        self.addr += bytes.len();
+        Ok(())
+    }
+
+    /// Evaluates `operand`. Returns None if it uses a symbol that is not
+    /// defined yet, in the first pass.
+    fn eval(&self, operand: &str) -> Result<Option<i64>, String> {
+        let mut parser = Parser {
+            text: operand.as_bytes(),
+            pos: 0,
+            asm: self,
+        };
+        let val = parser.expr()?;
+        parser.skip_spaces();
+        if parser.pos < parser.text.len() {
+            return Err(format!("Invalid expression {}", operand));
+        }
+        Ok(val)
+    }
+
+    /// Evaluates the single operand of an instruction taking `count`, the
+    /// one at `index`.
+    fn value(&self, operands: &[&str], count: usize, index: usize) -> Result<Option<i64>, String> {
+        if operands.len() != count {
+            return Err(format!("Expected {} operand(s)", count));
+        }
+        self.eval(operands[index])
+    }
+
+    /// Evaluates the only operand of ORG or DS, which can't depend on
+    /// later labels.
+    fn known(&self, operands: &[&str]) -> Result<i64, String> {
+        self.value(operands, 1, 0)?
+            .ok_or_else(|| "Value must be defined before use".to_string())
+    }
+
+    fn byte(&self, operand: &str) -> Result<u8, String> {
+        match self.eval(operand)? {
+            Some(val) if (-0x80..=0xFF).contains(&val) => Ok(val as u8),
+            Some(val) => Err(format!("Value out of range for a byte: {}", val)),
+            None => Ok(0),
+        }
+    }
+
+    fn word(&self, operand: &str) -> Result<u16, String> {
+        match self.eval(operand)? {
+            Some(val) if (-0x8000..=0xFFFF).contains(&val) => Ok(val as u16),
+            Some(val) => Err(format!("Value out of range for a word: {}", val)),
+            None => Ok(0),
+        }
+    }
+
+    fn register(&self, operand: &str) -> Result<u8, String> {
+        let name = operand.to_ascii_uppercase();
+        REGISTERS
+            .iter()
+            .position(|&reg| reg == name)
+            .map(|reg| reg as u8)
+            .ok_or_else(|| format!("Invalid register {}", operand))
+    }
+
+    /// The register pair `operand`, as encoded in bits 4-5. `last` is the
+    /// name of the fourth pair, SP or PSW.
+    fn pair(&self, operand: &str, last: &str) -> Result<u8, String> {
+        let name = operand.to_ascii_uppercase();
+        ["B", "D", "H", last]
+            .iter()
+            .position(|&pair| pair == name)
+            .map(|pair| (pair as u8) << 4)
+            .ok_or_else(|| format!("Invalid register pair {}", operand))
+    }
+
+    fn instruction(&self, mnemonic: &str, ops: &[&str]) -> Result<Vec<u8>, String> {
+        let find = |table: &[&str]| table.iter().position(|&m| m == mnemonic).map(|i| i as u8);
+        let count = |n: usize| {
+            if ops.len() == n {
+                Ok(())
+            } else {
+                Err(format!("{} takes {} operand(s)", mnemonic, n))
+            }
+        };
+        if let Some(&(_, opcode)) = IMPLIED.iter().find(|&&(name, _)| name == mnemonic) {
+            count(0)?;
+            return Ok(vec![opcode]);
+        }
+        if let Some(&(_, opcode)) = ADDRESSED.iter().find(|&&(name, _)| name == mnemonic) {
+            count(1)?;
+            let [low, high] = self.word(ops[0])?.to_le_bytes();
+            return Ok(vec![opcode, low, high]);
+        }
+        if let Some(&(_, opcode)) = PAIRED.iter().find(|&&(name, _)| name == mnemonic) {
+            let last = if opcode & 0xc0 == 0xc0 { "PSW" } else { "SP" };
+            let count_ops = if mnemonic == "LXI" { 2 } else { 1 };
+            count(count_ops)?;
+            let pair = self.pair(ops[0], last)?;
+            if (mnemonic == "STAX" || mnemonic == "LDAX") && pair > 0x10 {
+                return Err(format!("{} only takes B or D", mnemonic));
+            }
+            let mut bytes = vec![opcode | pair];
+            if mnemonic == "LXI" {
+                bytes.extend_from_slice(&self.word(ops[1])?.to_le_bytes());
+            }
+            return Ok(bytes);
+        }
+        if let Some(cond) = find(&RETURNS) {
+            count(0)?;
+            return Ok(vec![0xc0 | cond << 3]);
+        }
+        for &(table, base) in &[(&JUMPS, 0xc2), (&CALLS, 0xc4)] {
+            if let Some(cond) = find(table) {
+                count(1)?;
+                let [low, high] = self.word(ops[0])?.to_le_bytes();
+                return Ok(vec![base | cond << 3, low, high]);
+            }
+        }
+        if let Some(op) = find(&ALU) {
+            count(1)?;
+            return Ok(vec![0x80 | op << 3 | self.register(ops[0])?]);
+        }
+        if let Some(op) = find(&ALU_IMMEDIATE) {
+            count(1)?;
+            return Ok(vec![0xc6 | op << 3, self.byte(ops[0])?]);
+        }
+        match mnemonic {
+            "MOV" => {
+                count(2)?;
+                let (dest, src) = (self.register(ops[0])?, self.register(ops[1])?);
+                if dest == 6 && src == 6 {
+                    return Err("MOV M,M is not an instruction".to_string());
+                }
+                Ok(vec![0x40 | dest << 3 | src])
+            }
+            "MVI" => {
+                count(2)?;
+                Ok(vec![0x06 | self.register(ops[0])? << 3, self.byte(ops[1])?])
+            }
+            "INR" | "DCR" => {
+                count(1)?;
+                let opcode = if mnemonic == "INR" { 0x04 } else { 0x05 };
+                Ok(vec![opcode | self.register(ops[0])? << 3])
+            }
+            "IN" | "OUT" => {
+                count(1)?;
+                let opcode = if mnemonic == "IN" { 0xdb } else { 0xd3 };
+                Ok(vec![opcode, self.byte(ops[0])?])
+            }
+            "RST" => {
+                count(1)?;
+                match self.eval(ops[0])? {
+                    Some(num @ 0..=7) => Ok(vec![0xc7 | (num as u8) << 3]),
+                    Some(num) => Err(format!("Invalid restart {}", num)),
+                    None => Ok(vec![0xc7]),
+                }
+            }
+            _ => Err(format!("Unknown instruction {}", mnemonic)),
+        }
+    }
+}
+
+/// Binary operators, from lowest to highest precedence.
+const OPERATORS: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    asm: &'a Assembler,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_spaces();
+        if self.text[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes characters while `pred` holds.
+    fn take_while(&mut self, pred: impl Fn(u8) -> bool) -> &str {
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(|&c| pred(c)) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).unwrap()
+    }
+
+    fn expr(&mut self) -> Result<Option<i64>, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Option<i64>, String> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'operators: loop {
+            for &op in OPERATORS[level] {
+                if self.eat(op) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = match (lhs, rhs) {
+                        (Some(lhs), Some(rhs)) => Some(apply(op, lhs, rhs)?),
+                        _ => None,
+                    };
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, String> {
+        if self.eat("-") {
+            Ok(self.unary()?.map(i64::wrapping_neg))
+        } else if self.eat("+") {
+            self.unary()
+        } else if self.eat("~") {
+            Ok(self.unary()?.map(|val| !val))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Option<i64>, String> {
+        self.skip_spaces();
+        let c = *self.text.get(self.pos).ok_or("Missing operand")?;
+        match c {
+            b'(' => {
+                self.pos += 1;
+                let val = self.expr()?;
+                if !self.eat(")") {
+                    return Err("Missing )".to_string());
+                }
+                Ok(val)
+            }
+            b'\'' | b'"' => {
+                let text = &self.text[self.pos..];
+                match text {
+                    [q, c, q2, ..] if q == q2 => {
+                        self.pos += 3;
+                        Ok(Some(i64::from(*c)))
+                    }
+                    _ => Err("Invalid character constant".to_string()),
+                }
+            }
+            b'$' if !self
+                .text
+                .get(self.pos + 1)
+                .is_some_and(u8::is_ascii_hexdigit) =>
+            {
+                self.pos += 1;
+                Ok(Some(self.asm.start as i64))
+            }
+            b'$' | b'0'..=b'9' => {
+                self.pos += 1;
+                let rest = self.take_while(|c| c.is_ascii_alphanumeric());
+                let token = format!("{}{}", c as char, rest);
+                number(&token).map(Some)
+            }
+            _ if is_identifier_start(c) => {
+                let name = self.take_while(is_identifier).to_string();
+                match name.to_ascii_uppercase().as_str() {
+                    "HIGH" => Ok(self.unary()?.map(|val| (val >> 8) & 0xFF)),
+                    "LOW" => Ok(self.unary()?.map(|val| val & 0xFF)),
+                    _ => self.symbol(&name),
+                }
+            }
+            _ => Err(format!("Unexpected character {}", c as char)),
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<Option<i64>, String> {
+        let name = self.asm.qualify(name)?;
+        match self.asm.program.symbols.get(&name) {
+            Some(&val) => Ok(Some(val)),
+            None if !self.asm.final_pass => Ok(None),
+            None => Err(format!("Undefined symbol {}", name)),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    Ok(match op {
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "<<" => lhs.checked_shl(rhs as u32).unwrap_or(0),
+        ">>" => lhs.checked_shr(rhs as u32).unwrap_or(0),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        _ if rhs == 0 => return Err("Division by zero".to_string()),
+        "/" => lhs.wrapping_div(rhs),
+        _ => lhs.wrapping_rem(rhs),
+    })
+}
+
+#[test]
+fn assemble_documented_opcodes() {
+    use crate::dis::decode;
+    use crate::state::Model;
+
+    for opcode in 0..=255u8 {
+        let bytes = [opcode, 0x34, 0x12];
+        let instr = decode(&bytes, 0, Model::I8080);
+        if instr.is_undocumented() {
+            continue;
+        }
+        let program = assemble(&format!("    {}", instr)).unwrap();
+        let expected = &bytes[..usize::from(instr.length)];
+        assert_eq!(program.binary(), expected, "Wrong encoding for {}", instr);
+    }
+}
+
+#[test]
+fn labels_and_expressions() {
+    let program = assemble(
+        "
+; Comment
+COUNT   EQU     END_ - START
+        ORG     100h
+START:  LXI     H,table
+        MVI     B,COUNT
+.loop:  DCR     B
+        JNZ     .loop
+Next    JMP     .loop
+.loop   DB      'Hi', 0, ';', LOW($ + 1)
+table:  DW      HIGH 1234h, -1
+        DS      2
+END_:   RST     (3 << 1) | 1
+",
+    )
+    .unwrap();
+    assert_eq!(program.origin(), 0x100);
+    assert_eq!(program.symbol("START.loop"), Some(0x105));
+    assert_eq!(program.symbol("Next.loop"), Some(0x10c));
+    assert_eq!(program.symbol("COUNT"), Some(0x17));
+    #[rustfmt::skip]
+    assert_eq!(
+        program.binary(),
+        [
+            0x21, 0x11, 0x01, // LXI H,table
+            0x06, 0x17,       // MVI B,COUNT
+            0x05,             // DCR B
+            0xc2, 0x05, 0x01, // JNZ START.loop
+            0xc3, 0x0c, 0x01, // JMP Next.loop
+            b'H', b'i', 0, b';', 0x0d,
+            0x12, 0x00, 0xff, 0xff,
+            0x00, 0x00,
+            0xff,             // RST 7
+        ]
+    );
+    assert_eq!(program.segments().count(), 2);
+}
+
+#[test]
+fn errors() {
+    let err = assemble("  NOP\n  MOV M,M\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert_eq!(
+        assemble("  JMP nowhere").unwrap_err().message,
+        "Undefined symbol nowhere"
+    );
+    assert!(assemble("a: NOP\na: NOP").is_err());
+    assert!(assemble("a EQU later\na EQU 2\nlater:").is_err());
+    assert!(assemble("a EQU 2\na EQU later\nlater:").is_err());
+    assert!(assemble("a EQU later\na: NOP\nlater:").is_err());
+    assert!(assemble("  MVI A,256").is_err());
+    assert!(assemble("  ORG later\nlater:").is_err());
+    assert!(assemble("  STAX H").is_err());
+    assert!(assemble("  FOO").is_err());
+    assert!(assemble("  ORG 0FFFFh\n  JMP 0").is_err());
+    assert!(assemble("  DW -(1 << 63)").is_err());
+    assert!(assemble("  DW 0 - (1 << 63) / -1").is_err());
+    assert!(assemble("  RIM").is_err());
+}
+
+#[test]
+fn number_formats() {
+    let program = assemble("  DB 0FFh, $fe, 0xFD, 11111100b, 373o, 250, 'A'").unwrap();
+    assert_eq!(program.binary(), [0xff, 0xfe, 0xfd, 0xfc, 0xfb, 0xfa, 0x41]);
+}
+
+#[test]
+fn intel_hex_output() {
+    let program = assemble("  ORG 100h\n  MVI A,1\n  HLT\n  ORG 200h\n  DB 1,2").unwrap();
+    assert_eq!(
+        program.intel_hex(),
+        ":030100003E017647\n:020200000102F9\n:00000001FF\n"
+    );
+}
+
+#[test]
+fn run_assembled_program() {
+    use crate::{DefaultHandler, Emu8080};
+
+    // Sums 1 to 10
+    let program = assemble(
+        "
+        ORG     0
+        MVI     B,10
+        XRA     A
+loop:   ADD     B
+        DCR     B
+        JNZ     loop
+        HLT
+",
+    )
+    .unwrap();
+    let mut emu = Emu8080::new(DefaultHandler);
+    emu.load_program(&program);
+    while !emu.halted {
+        emu.step();
+    }
+    assert_eq!(emu.a, 55);
+    assert_eq!(program.symbols().name(0x03), Some("loop"));
+}
@@ -1,44 +1,35 @@
-use emulator::{DefaultHandler, Emu8080};
+use emulator::cpm::{CpmExit, CpmMachine};
+use std::env::args;
+use std::fs;
 
-pub static DIAG_BYTES: &'static [u8] = include_bytes!("cpudiag.bin");
+pub static DIAG_BYTES: &[u8] = include_bytes!("cpudiag.bin");
 
-pub fn run_diag() {
-    let mut emu = Emu8080::new(DefaultHandler);
-    emu.memory[0x100..(0x100 + DIAG_BYTES.len())].copy_from_slice(DIAG_BYTES);
-    emu.memory[368] = 0x7;
-    //Skip DAA test
-    emu.memory[0x59c] = 0xc3; //JMP
-    emu.memory[0x59d] = 0xc2;
-    emu.memory[0x59e] = 0x05;
-
-    emu.pc = 0x100;
-    while emu.step_dis() == 0 {
-        if emu.pc == 0x0689 {
-            // Error procedure
-            eprintln!("\x1b[1;31mDiagnostic failed\x1b[0m");
-            return;
-        } else if emu.pc == 0x069B {
-            // Success procedure
+pub fn run_diag(image: &[u8]) {
+    let mut machine = CpmMachine::new(image);
+    machine.emu.trace = true;
+    let exit = machine.run(u64::MAX);
+    let failed: Vec<_> = machine
+        .sub_tests()
+        .into_iter()
+        .filter(|t| !t.passed)
+        .collect();
+    for test in &failed {
+        eprintln!("{}: {}", test.name, test.result);
+    }
+    // Programs without separate tests print a single verdict
+    let verdict_failed = machine.output().contains("FAILED");
+    match exit {
+        Ok(CpmExit::WarmBoot) if failed.is_empty() && !verdict_failed => {
             eprintln!("\x1b[1;32mDiagnostic successful\x1b[0m");
-            return;
-        } else if emu.pc == 5 {
-            // Print routine
-            if emu.c == 9 {
-                let mut s = emu.de();
-                while emu.memory[s] != b'$' {
-                    print!("{}", emu.memory[s] as char);
-                    s += 1;
-                }
-                println!();
-            }
-            emu.ret();
-        } else if emu.pc == 0 {
-            // End of program
-            return;
         }
+        Ok(_) => eprintln!("\x1b[1;31mDiagnostic failed\x1b[0m"),
+        Err(err) => eprintln!("\x1b[1;31mDiagnostic failed: {}\x1b[0m", err),
     }
 }
 
 fn main() {
-    run_diag();
+    match args().nth(1) {
+        Some(filename) => run_diag(&fs::read(filename).expect("Could not read program")),
+        None => run_diag(DIAG_BYTES),
+    }
 }
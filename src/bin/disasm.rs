@@ -0,0 +1,65 @@
+use emulator::dis::{Disassembly, Format, Hex, Syntax};
+use emulator::state::Model;
+use emulator::symbols::Symbols;
+use std::env::args;
+use std::fs;
+use std::process::exit;
+
+/// Reset and interrupt vectors of the Space Invaders ROM.
+const DEFAULT_ENTRIES: [u16; 3] = [0x00, 0x08, 0x10];
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: disasm [-s symbols] [--cpu 8080|8085|z80] [--zilog] [--lowercase] \
+         [--hex suffix|dollar|prefix] <image> [entry...]"
+    );
+    exit(1);
+}
+
+fn main() {
+    let mut symbols = Symbols::new();
+    let mut format = Format::default();
+    let mut model = Model::I8080;
+    let mut args = args().skip(1);
+    let filename = loop {
+        match args.next().as_deref() {
+            Some("-s") | Some("--symbols") => {
+                let path = args.next().unwrap_or_else(|| usage());
+                symbols = Symbols::load(path).expect("Could not load symbols");
+            }
+            Some("--cpu") => {
+                model = match args.next().as_deref() {
+                    Some("8080") => Model::I8080,
+                    Some("8085") => Model::I8085,
+                    Some("z80") | Some("Z80") => {
+                        format.syntax = Syntax::Zilog;
+                        Model::Z80
+                    }
+                    _ => usage(),
+                }
+            }
+            Some("-z") | Some("--zilog") => format.syntax = Syntax::Zilog,
+            Some("-l") | Some("--lowercase") => format.lowercase = true,
+            Some("--hex") => {
+                format.hex = match args.next().as_deref() {
+                    Some("suffix") => Hex::Suffix,
+                    Some("dollar") => Hex::Dollar,
+                    Some("prefix") => Hex::Prefix,
+                    _ => usage(),
+                }
+            }
+            Some(filename) if !filename.starts_with('-') => break filename.to_string(),
+            _ => usage(),
+        }
+    };
+    let image = fs::read(filename).expect("Could not read image");
+    let mut entries: Vec<u16> = args
+        .map(|arg| u16::from_str_radix(arg.trim_start_matches("0x"), 16).expect("Invalid entry"))
+        .collect();
+    if entries.is_empty() {
+        entries.extend_from_slice(&DEFAULT_ENTRIES);
+    }
+    let mut dis = Disassembly::with_model(&image, 0, &entries, &symbols, model);
+    dis.format = format;
+    print!("{}", dis);
+}
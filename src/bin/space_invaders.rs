@@ -1,4 +1,6 @@
-use emulator::*;
+use emulator::{
+    interrupt::RstInterrupts, memory::MirroredMemory, state::RomPolicy, symbols::Symbols, *,
+};
 use sdl2::{
     event::Event,
     keyboard::Keycode,
@@ -8,6 +10,16 @@ use sdl2::{
 };
 use std::env::args;
 
+/// Cycles per frame of the 2 MHz CPU at 60 Hz.
+const FRAME_CYCLES: u64 = 2_000_000 / 60;
+/// Scanlines per frame, including vertical blanking.
+const SCANLINES: u64 = 262;
+
+/// Cycle at which the beam reaches `line` in the first frame.
+const fn scanline(line: u64) -> u64 {
+    FRAME_CYCLES * line / SCANLINES
+}
+
 const COLORS: [Color; 4] = [
     Color {
         r: 0x00,
@@ -97,7 +109,7 @@ struct SpaceInvadersInOut {
 }
 
 impl InOutHandler for SpaceInvadersInOut {
-    fn read(&mut self, port: u8) -> u8 {
+    fn read(&mut self, port: u8, _cycles: u64) -> u8 {
         match port {
             0 => 14,
             1 => self.port1,
@@ -107,7 +119,7 @@ impl InOutHandler for SpaceInvadersInOut {
         }
     }
 
-    fn write(&mut self, port: u8, val: u8) {
+    fn write(&mut self, port: u8, val: u8, _cycles: u64) {
         match port {
             4 => {
                 self.xy = (self.xy >> 8) | (u16::from(val) << 8);
@@ -199,9 +211,9 @@ fn update_display(
     event_pump: &sdl2::EventPump,
     window: &Window,
     &mut (ref mut game_surface, ref mut temp_surface): &mut (Surface<'static>, Surface<'static>),
-    emu: &Emu8080<SpaceInvadersInOut>,
+    emu: &Emu8080<SpaceInvadersInOut, MirroredMemory, RstInterrupts>,
 ) {
-    let mut window_surface = window.surface(&event_pump).unwrap();
+    let mut window_surface = window.surface(event_pump).unwrap();
     let display_buffer = &emu.memory[0x2400..][..((WINDOW_WIDTH * WINDOW_HEIGHT) / 8)];
     display_window(display_buffer, game_surface);
     game_surface.blit(None, temp_surface, None).unwrap();
@@ -211,21 +223,72 @@ fn update_display(
     window_surface.finish().unwrap();
 }
 
+type Machine = Emu8080<SpaceInvadersInOut, MirroredMemory, RstInterrupts>;
+
+/// The game's hardware, raising RST 1 when the beam reaches mid screen and
+/// RST 2 at the start of VBLANK.
+fn new_machine() -> Machine {
+    // 8K of ROM followed by 8K of RAM, mirrored across the address space
+    let mut emu = Emu8080::with_interrupts(
+        SpaceInvadersInOut::default(),
+        MirroredMemory::new(0x4000),
+        RstInterrupts::default(),
+    );
+    for &(line, vector) in &[(96, 1), (224, 2)] {
+        emu.schedule(scanline(line), move |emu, at| {
+            emu.interrupts.request(vector);
+            Some(at + FRAME_CYCLES)
+        });
+    }
+    emu
+}
+
+/// Write-protects the ROM once loaded. The game writes past the end of
+/// RAM into the mirrors of the ROM during normal play, which the hardware
+/// ignores.
+fn protect_rom(emu: &mut Machine) {
+    emu.protect(0..0x2000, RomPolicy::Error);
+    for mirror in (0x4000..0x10000).step_by(0x4000) {
+        emu.protect(mirror..(mirror + 0x2000), RomPolicy::Ignore);
+    }
+}
+
+/// Runs the game until `end` cycles, checking that the program counter
+/// stays within the game ROM before every instruction.
+fn run_to(emu: &mut Machine, end: u64) -> Result<(), String> {
+    while emu.cycles < end {
+        let outcome = emu.run_until(end - emu.cycles, |state| state.pc > 0x1FFF);
+        if let StopReason::Error(err) = outcome.stop {
+            return Err(err.to_string());
+        }
+        if emu.pc > 0x1FFF {
+            return Err(format!("Program counter out of game rom: {:04X}", emu.pc));
+        }
+    }
+    Ok(())
+}
+
 fn main() {
-    let mut emu = Emu8080::<SpaceInvadersInOut>::default();
+    let mut emu = new_machine();
     let mut filename = None;
     let mut disassemble = false;
-    for arg in args().skip(1) {
+    let mut options = args().skip(1);
+    while let Some(arg) = options.next() {
         if arg == "-d" || arg == "--disassemble" {
             disassemble = true;
+        } else if arg == "-s" || arg == "--symbols" {
+            let path = options.next().expect("Missing symbol file");
+            emu.symbols = Symbols::load(path).expect("Could not load symbols");
         } else {
             filename = filename.or(Some(arg));
         }
     }
+    emu.trace = disassemble;
     if let Some(filename) = filename {
         emu.read_file_in_memory_at(&filename, 0).unwrap();
+        protect_rom(&mut emu);
     } else {
-        eprintln!("Usage: {} rom", args().next().unwrap());
+        eprintln!("Usage: {} [-d] [-s symbols] rom", args().next().unwrap());
         std::process::exit(1);
     }
 
@@ -234,13 +297,11 @@ fn main() {
         .0
         .set_palette(&Palette::with_colors(&COLORS).unwrap())
         .expect("Could not set color palette");
-    let mut cycles = 0;
-    let mut n: u64 = 0;
+    let mut frame_end = 0;
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = init_window(&video_subsystem);
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut next_interrupt = 1;
     loop {
         for event in event_pump.poll_iter() {
             match event {
@@ -262,26 +323,20 @@ fn main() {
                 _ => {}
             }
         }
-        if emu.pc > 0x1FFF {
-            panic!("Program counter out of game rom: {:04X}", emu.pc);
+        frame_end += FRAME_CYCLES;
+        if let Err(err) = run_to(&mut emu, frame_end) {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
-
-        if cycles > 16666 {
-            cycles -= 16667;
-            if emu.int_enable {
-                update_display(&event_pump, &window, &mut surfaces, &emu);
-                emu.generate_interrupt(next_interrupt);
-                next_interrupt = if next_interrupt == 1 { 2 } else { 1 };
-            }
-        }
-        if disassemble {
-            print!("#{} ", n);
-        }
-        n += 1;
-        cycles += if disassemble {
-            emu.step_dis()
-        } else {
-            emu.step()
-        };
+        update_display(&event_pump, &window, &mut surfaces, &emu);
     }
 }
+
+#[test]
+fn runs_attract_mode() {
+    let mut emu = new_machine();
+    emu.load_at(0, include_bytes!("../invaders.rom"));
+    protect_rom(&mut emu);
+    // Well past the first write into a mirror of the ROM, at frame 2874
+    assert_eq!(run_to(&mut emu, 3600 * FRAME_CYCLES), Ok(()));
+}
@@ -0,0 +1,179 @@
+//! A minimal CP/M environment, enough to run the standard 8080 exercisers
+//! (TST8080, 8080PRE, CPUTEST, 8080EXM) and ZEXDOC from their `.COM` images.
+
+use crate::error::StepError;
+use crate::interrupt::NoInterrupts;
+use crate::memory::FlatMemory;
+use crate::state::Model;
+use crate::{DefaultHandler, Emu8080, StopReason};
+
+/// Entry point of the BDOS, called by programs for console output.
+const BDOS: usize = 0x0005;
+/// Top of the transient program area. Programs read it from the operand of
+/// the jump at `BDOS` to set up their stack.
+const TPA_TOP: usize = 0xFE00;
+
+/// Why `CpmMachine::run` returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpmExit {
+    /// The program jumped to the warm boot vector at address 0.
+    WarmBoot,
+    /// The cycle budget was exhausted before the program exited.
+    OutOfCycles,
+}
+
+pub struct CpmMachine {
+    pub emu: Emu8080,
+    /// Echo console output to stdout as it is produced.
+    pub echo: bool,
+    output: String,
+}
+
+impl CpmMachine {
+    /// Loads `image` at 0x100 and sets up the warm boot and BDOS vectors.
+    pub fn new(image: &[u8]) -> Self {
+        CpmMachine::with_model(image, Model::I8080)
+    }
+
+    /// Same as `new`, running the program on the CPU `model`.
+    pub fn with_model(image: &[u8], model: Model) -> Self {
+        let mut emu =
+            Emu8080::with_model(DefaultHandler, FlatMemory::default(), NoInterrupts, model);
+        emu.load_at(0x100, image);
+        emu.load_at(0x0000, &[0x76]); // HLT
+        emu.load_at(BDOS, &[0xc3, TPA_TOP as u8, (TPA_TOP >> 8) as u8]); // JMP TPA_TOP
+        emu.load_at(TPA_TOP, &[0xc9]); // RET
+
+        // Returning from the program warm boots
+        emu.load_at(TPA_TOP - 2, &[0x00, 0x00]);
+        emu.sp = TPA_TOP - 2;
+        emu.pc = 0x100;
+        CpmMachine {
+            emu,
+            echo: true,
+            output: String::new(),
+        }
+    }
+
+    /// Runs the program until it exits or `max_cycles` have elapsed.
+    ///
+    /// Console output is echoed to stdout as it is produced unless `echo` is
+    /// unset, so test results are visible while long exercisers are running.
+    pub fn run(&mut self, max_cycles: u64) -> Result<CpmExit, StepError> {
+        while self.emu.cycles < max_cycles {
+            match self.emu.pc {
+                0 => return Ok(CpmExit::WarmBoot),
+                BDOS => self.bdos(),
+                _ => {}
+            }
+            let outcome = self.emu.run_until(max_cycles - self.emu.cycles, |state| {
+                matches!(state.pc, 0 | BDOS)
+            });
+            if let StopReason::Error(err) = outcome.stop {
+                return Err(err);
+            }
+        }
+        Ok(CpmExit::OutOfCycles)
+    }
+
+    fn bdos(&mut self) {
+        match self.emu.c {
+            0 => {
+                // System reset
+                self.emu.pc = 0;
+            }
+            2 => {
+                // Console output
+                let c = self.emu.e as char;
+                self.print(c);
+            }
+            9 => {
+                // Print string
+                let mut addr = self.emu.de();
+                loop {
+                    let c = self.emu.memory[addr];
+                    if c == b'$' {
+                        break;
+                    }
+                    self.print(c as char);
+                    addr = (addr + 1) & 0xFFFF;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.echo {
+            print!("{}", c);
+        }
+        self.output.push(c);
+    }
+
+    /// Everything the program printed on the console.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Cycles elapsed since the program started.
+    pub fn cycles(&self) -> u64 {
+        self.emu.cycles
+    }
+
+    /// The tests the program reported on lines of the form
+    /// `name....  OK` or `name....  ERROR ...`, in order.
+    pub fn sub_tests(&self) -> Vec<SubTest> {
+        self.output.lines().filter_map(SubTest::parse).collect()
+    }
+}
+
+/// The result of one of the tests run by an exerciser.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubTest {
+    pub name: String,
+    pub passed: bool,
+    /// What was printed after the name, such as the expected and found
+    /// CRCs of a failed test.
+    pub result: String,
+}
+
+impl SubTest {
+    fn parse(line: &str) -> Option<SubTest> {
+        let (name, rest) = line.split_at(line.find("...")?);
+        let result = rest.trim_start_matches('.').trim();
+        let passed = result == "OK";
+        if !passed && !result.starts_with("ERROR") {
+            return None;
+        }
+        Some(SubTest {
+            name: name.trim().to_string(),
+            passed,
+            result: result.to_string(),
+        })
+    }
+}
+
+#[test]
+fn parse_sub_tests() {
+    let mut machine = CpmMachine::new(&[]);
+    machine.output = "8080 instruction exerciser\r\n\
+        dad <b,d,h,sp>................  OK\r\n\
+        aluop nn......................  ERROR **** crc expected:9e922f9e found:01234567\r\n\
+        Tests complete\r\n"
+        .to_string();
+    assert_eq!(
+        machine.sub_tests(),
+        vec![
+            SubTest {
+                name: "dad <b,d,h,sp>".to_string(),
+                passed: true,
+                result: "OK".to_string(),
+            },
+            SubTest {
+                name: "aluop nn".to_string(),
+                passed: false,
+                result: "ERROR **** crc expected:9e922f9e found:01234567".to_string(),
+            },
+        ]
+    );
+}
@@ -1,427 +1,1269 @@
-pub fn disassemble8080_op(codebuffer: &[u8], pc: usize) -> usize {
-    let code = &codebuffer[pc..];
-    let mut opbytes = 1;
-    print!("{:04X} {:02X} ", pc, code[0]);
-    match code[0] {
-        0x00 => print!("NOP"),
-        0x01 => {
-            print!("LXI    B,#${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x02 => print!("STAX   B"),
-        0x03 => print!("INX   B"),
-        0x04 => print!("INR   B"),
-        0x05 => print!("DCR   B"),
-        0x06 => {
-            print!("MVI    B,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x07 => print!("RLC"),
-
-        0x08 => print!("NOP"),
-        0x09 => print!("DAD   B"),
-        0x0a => print!("LDAX   B"),
-        0x0b => print!("DCX   C"),
-        0x0c => print!("INR   C"),
-        0x0d => print!("DCR   C"),
-        0x0e => {
-            print!("MVI    C,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x0f => print!("RRC"),
+//! Decoding of 8080, 8085 and Z80 instructions, for tracing and listings.
 
-        0x10 => print!("NOP"),
-        0x11 => {
-            print!("LXI    D,#${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x12 => print!("STAX   D"),
-        0x13 => print!("INX   D"),
-        0x14 => print!("INR   D"),
-        0x15 => print!("DCR   D"),
-        0x16 => {
-            print!("MVI    D,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x17 => print!("RAL"),
-
-        0x18 => print!("NOP"),
-        0x19 => print!("DAD   D"),
-        0x1a => print!("LDAX   D"),
-        0x1b => print!("DCX   D"),
-        0x1c => print!("INR   E"),
-        0x1d => print!("DCR   E"),
-        0x1e => {
-            print!("MVI    E,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x1f => print!("RAR"),
+use std::collections::BTreeMap;
+use std::fmt;
 
-        0x20 => print!("NOP"),
-        0x21 => {
-            print!("LXI    H,#${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x22 => {
-            print!("SHLD   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x23 => print!("INX   H"),
-        0x24 => print!("INR   H"),
-        0x25 => print!("DCR   H"),
-        0x26 => {
-            print!("MVI    H,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x27 => print!("DAA"),
+use crate::opcodes::opcodes;
+use crate::state::Model;
+use crate::symbols::Symbols;
+use crate::z80::uses_memory_at_hl;
 
-        0x28 => print!("NOP"),
-        0x29 => print!("DAD   H"),
-        0x2a => {
-            print!("LHLD   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x2b => print!("DCX    H"),
-        0x2c => print!("INR   L"),
-        0x2d => print!("DCR   L"),
-        0x2e => {
-            print!("MVI    L,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x2f => print!("CMA"),
+static NO_SYMBOLS: Symbols = Symbols::new();
 
-        0x30 => print!("NOP"),
-        0x31 => {
-            print!("LXI   SP,#${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x32 => {
-            print!("STA   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x33 => print!("INX  SP"),
-        0x34 => print!("INR   M"),
-        0x35 => print!("DCR   M"),
-        0x36 => {
-            print!("MVI    M,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x37 => print!("STC"),
+/// An 8 bit register, as encoded in opcodes. `M` is the memory at HL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M,
+    A,
+}
 
-        0x38 => print!("NOP"),
-        0x39 => print!("DAD  SP"),
-        0x3a => {
-            print!("LDA    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0x3b => print!("DCX   SP"),
-        0x3c => print!("INR   A"),
-        0x3d => print!("DCR   A"),
-        0x3e => {
-            print!("MVI    A,#${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0x3f => print!("CMC"),
-
-        0x40 => print!("MOV   B,B"),
-        0x41 => print!("MOV   B,C"),
-        0x42 => print!("MOV   B,D"),
-        0x43 => print!("MOV   B,E"),
-        0x44 => print!("MOV   B,H"),
-        0x45 => print!("MOV   B,L"),
-        0x46 => print!("MOV   B,M"),
-        0x47 => print!("MOV   B,A"),
-        0x48 => print!("MOV   C,B"),
-        0x49 => print!("MOV   C,C"),
-        0x4a => print!("MOV   C,D"),
-        0x4b => print!("MOV   C,E"),
-        0x4c => print!("MOV   C,H"),
-        0x4d => print!("MOV   C,L"),
-        0x4e => print!("MOV   C,M"),
-        0x4f => print!("MOV   C,A"),
-
-        0x50 => print!("MOV   D,B"),
-        0x51 => print!("MOV   D,C"),
-        0x52 => print!("MOV   D,D"),
-        0x53 => print!("MOV   D,E"),
-        0x54 => print!("MOV   D,H"),
-        0x55 => print!("MOV   D,L"),
-        0x56 => print!("MOV   D,M"),
-        0x57 => print!("MOV   D,A"),
-        0x58 => print!("MOV   E,B"),
-        0x59 => print!("MOV   E,C"),
-        0x5a => print!("MOV   E,D"),
-        0x5b => print!("MOV   E,E"),
-        0x5c => print!("MOV   E,H"),
-        0x5d => print!("MOV   E,L"),
-        0x5e => print!("MOV   E,M"),
-        0x5f => print!("MOV   E,A"),
-
-        0x60 => print!("MOV   H,B"),
-        0x61 => print!("MOV   H,C"),
-        0x62 => print!("MOV   H,D"),
-        0x63 => print!("MOV   H,E"),
-        0x64 => print!("MOV   H,H"),
-        0x65 => print!("MOV   H,L"),
-        0x66 => print!("MOV   H,M"),
-        0x67 => print!("MOV   H,A"),
-        0x68 => print!("MOV   L,B"),
-        0x69 => print!("MOV   L,C"),
-        0x6a => print!("MOV   L,D"),
-        0x6b => print!("MOV   L,E"),
-        0x6c => print!("MOV   L,H"),
-        0x6d => print!("MOV   L,L"),
-        0x6e => print!("MOV   L,M"),
-        0x6f => print!("MOV   L,A"),
-
-        0x70 => print!("MOV   M,B"),
-        0x71 => print!("MOV   M,C"),
-        0x72 => print!("MOV   M,D"),
-        0x73 => print!("MOV   M,E"),
-        0x74 => print!("MOV   M,H"),
-        0x75 => print!("MOV   M,L"),
-        0x76 => print!("HLT"),
-        0x77 => print!("MOV   M,A"),
-        0x78 => print!("MOV   A,B"),
-        0x79 => print!("MOV   A,C"),
-        0x7a => print!("MOV   A,D"),
-        0x7b => print!("MOV   A,E"),
-        0x7c => print!("MOV   A,H"),
-        0x7d => print!("MOV   A,L"),
-        0x7e => print!("MOV   A,M"),
-        0x7f => print!("MOV   A,A"),
-
-        0x80 => print!("ADD   B"),
-        0x81 => print!("ADD   C"),
-        0x82 => print!("ADD   D"),
-        0x83 => print!("ADD   E"),
-        0x84 => print!("ADD   H"),
-        0x85 => print!("ADD   L"),
-        0x86 => print!("ADD   M"),
-        0x87 => print!("ADD   A"),
-        0x88 => print!("ADC   B"),
-        0x89 => print!("ADC   C"),
-        0x8a => print!("ADC   D"),
-        0x8b => print!("ADC   E"),
-        0x8c => print!("ADC   H"),
-        0x8d => print!("ADC   L"),
-        0x8e => print!("ADC   M"),
-        0x8f => print!("ADC   A"),
-
-        0x90 => print!("SUB   B"),
-        0x91 => print!("SUB   C"),
-        0x92 => print!("SUB   D"),
-        0x93 => print!("SUB   E"),
-        0x94 => print!("SUB   H"),
-        0x95 => print!("SUB   L"),
-        0x96 => print!("SUB   M"),
-        0x97 => print!("SUB   A"),
-        0x98 => print!("SBB   B"),
-        0x99 => print!("SBB   C"),
-        0x9a => print!("SBB   D"),
-        0x9b => print!("SBB   E"),
-        0x9c => print!("SBB   H"),
-        0x9d => print!("SBB   L"),
-        0x9e => print!("SBB   M"),
-        0x9f => print!("SBB   A"),
-
-        0xa0 => print!("ANA   B"),
-        0xa1 => print!("ANA   C"),
-        0xa2 => print!("ANA   D"),
-        0xa3 => print!("ANA   E"),
-        0xa4 => print!("ANA   H"),
-        0xa5 => print!("ANA   L"),
-        0xa6 => print!("ANA   M"),
-        0xa7 => print!("ANA   A"),
-        0xa8 => print!("XRA   B"),
-        0xa9 => print!("XRA   C"),
-        0xaa => print!("XRA   D"),
-        0xab => print!("XRA   E"),
-        0xac => print!("XRA   H"),
-        0xad => print!("XRA   L"),
-        0xae => print!("XRA   M"),
-        0xaf => print!("XRA   A"),
-
-        0xb0 => print!("ORA   B"),
-        0xb1 => print!("ORA   C"),
-        0xb2 => print!("ORA   D"),
-        0xb3 => print!("ORA   E"),
-        0xb4 => print!("ORA   H"),
-        0xb5 => print!("ORA   L"),
-        0xb6 => print!("ORA   M"),
-        0xb7 => print!("ORA   A"),
-        0xb8 => print!("CMP   B"),
-        0xb9 => print!("CMP   C"),
-        0xba => print!("CMP   D"),
-        0xbb => print!("CMP   E"),
-        0xbc => print!("CMP   H"),
-        0xbd => print!("CMP   L"),
-        0xbe => print!("CMP   M"),
-        0xbf => print!("CMP   A"),
-
-        0xc0 => print!("RNZ"),
-        0xc1 => print!("POP   B"),
-        0xc2 => {
-            print!("JNZ    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0xc3 => {
-            print!("JMP    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0xc4 => {
-            print!("CNZ    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0xc5 => print!("PUSH  B"),
-        0xc6 => {
-            print!("ADI    #${:02X}", code[1]);
-            opbytes = 2;
-        }
-        0xc7 => print!("RST   0"),
-        0xc8 => print!("RZ"),
-        0xc9 => print!("RET"),
-        0xca => {
-            print!("JZ     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+const REGISTERS: [Register; 8] = [
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    Register::M,
+    Register::A,
+];
+
+/// A register pair, named after its high register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterPair {
+    B,
+    D,
+    H,
+    SP,
+    /// A and the flags, for PUSH and POP.
+    PSW,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    Pair(RegisterPair),
+    Immediate(u8),
+    Immediate16(u16),
+    /// A memory address, jumped to or accessed.
+    Address(u16),
+    /// An I/O port, for IN and OUT.
+    Port(u8),
+    /// The number of an RST instruction.
+    Restart(u8),
+    /// A register, pair or condition of a Z80 instruction, by name.
+    Name(&'static str),
+    /// The memory at an address, for Z80 instructions, `(nn)`.
+    Indirect(u16),
+    /// The memory at IX or IY plus a displacement, `(IX+d)`.
+    Indexed(&'static str, i8),
+    /// A number written in decimal: the bit of BIT, RES and SET, or the
+    /// mode of IM.
+    Number(u8),
+}
+
+/// How an instruction affects the flow of control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Execution continues with the next instruction.
+    Next,
+    Jump,
+    ConditionalJump,
+    Call,
+    ConditionalCall,
+    Return,
+    ConditionalReturn,
+    /// RST, a call to a fixed address.
+    Restart,
+    /// PCHL, a jump to an address only known at run time.
+    IndirectJump,
+    Halt,
+}
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// Address the instruction was decoded from.
+    pub addr: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    /// Length in bytes, including operands.
+    pub length: u8,
+    /// Cycles taken, or for conditional CALL and RET, cycles taken when the
+    /// condition is not met.
+    pub cycles: u8,
+    /// Cycles taken by conditional CALL and RET when the condition is met.
+    pub cycles_taken: u8,
+    pub flow: Flow,
+    /// Syntax of `mnemonic` and `operands`. Instructions only the Z80 has
+    /// are decoded in Zilog syntax, and always written in it.
+    pub syntax: Syntax,
+    undocumented: bool,
+}
+
+impl Instruction {
+    /// The address jumped to or called, if known before running the
+    /// instruction.
+    pub fn target(&self) -> Option<u16> {
+        match (self.flow, self.operands.last()) {
+            (Flow::Restart, Some(&Operand::Restart(num))) => Some(u16::from(num) * 8),
+            (
+                Flow::Jump | Flow::ConditionalJump | Flow::Call | Flow::ConditionalCall,
+                Some(&Operand::Address(addr)),
+            ) => Some(addr),
+            _ => None,
         }
-        0xcb => print!("NOP"),
-        0xcc => {
-            print!("CZ     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    }
+
+    /// Whether the instruction is missing from the documented instruction
+    /// set of the CPU it was decoded for. The 8080 executes such opcodes as
+    /// the instructions they are decoded to.
+    pub fn is_undocumented(&self) -> bool {
+        self.undocumented
+    }
+
+    /// Displays the instruction in `format`, with names from `symbols`.
+    pub fn display<'a>(&'a self, format: Format, symbols: &'a Symbols) -> Formatted<'a> {
+        Formatted {
+            instr: self,
+            format,
+            symbols,
         }
-        0xcd => {
-            print!("CALL   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for RegisterPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&Format::default().operand(*self, &|_| None))
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(Format::default(), &NO_SYMBOLS).fmt(f)
+    }
+}
+
+/// Mnemonics and operand names used to write instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// The 8080's own mnemonics, `MOV A,M`.
+    #[default]
+    Intel,
+    /// The Z80 mnemonics for the same instructions, `LD A,(HL)`.
+    Zilog,
+}
+
+/// How hexadecimal numbers are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Hex {
+    /// `0FFh`
+    #[default]
+    Suffix,
+    /// `$FF`
+    Dollar,
+    /// `0xFF`
+    Prefix,
+}
+
+/// How instructions are written by the disassembler.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Format {
+    pub syntax: Syntax,
+    pub hex: Hex,
+    /// Write mnemonics, registers and hexadecimal digits in lowercase.
+    /// Symbol names are kept as they are.
+    pub lowercase: bool,
+}
+
+/// Width of the mnemonic column. Operands always start after it.
+const MNEMONIC_WIDTH: usize = 8;
+
+/// An operand as written in a given syntax.
+enum Part {
+    /// A register, pair or condition name.
+    Text(&'static str),
+    Operand(Operand),
+    /// A memory or port operand, in parentheses.
+    Indirect(Operand),
+}
+
+impl Format {
+    /// `val` as a hexadecimal number of `digits` digits.
+    fn hex(&self, val: u16, digits: usize) -> String {
+        let text = if self.lowercase {
+            format!("{:0width$x}", val, width = digits)
+        } else {
+            format!("{:0width$X}", val, width = digits)
+        };
+        match self.hex {
+            // Numbers must start with a digit to be told apart from names
+            Hex::Suffix if text.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                format!("0{}h", text)
+            }
+            Hex::Suffix => format!("{}h", text),
+            Hex::Dollar => format!("${}", text),
+            Hex::Prefix => format!("0x{}", text),
         }
-        0xce => {
-            print!("ACI    #${:02X}", code[1]);
-            opbytes = 2;
+    }
+
+    /// A mnemonic, register or directive, in the chosen case.
+    fn keyword(&self, text: &str) -> String {
+        if self.lowercase {
+            text.to_ascii_lowercase()
+        } else {
+            text.to_string()
         }
-        0xcf => print!("RST   1"),
+    }
 
-        0xd0 => print!("RNC"),
-        0xd1 => print!("POP   D"),
-        0xd2 => {
-            print!("JNC    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    /// `operand`, with addresses named by `name` when it knows them.
+    fn operand(&self, operand: Operand, name: &dyn Fn(u16) -> Option<String>) -> String {
+        match operand {
+            Operand::Register(reg) => self.keyword(&reg.to_string()),
+            Operand::Pair(pair) => self.keyword(&pair.to_string()),
+            Operand::Immediate(val) | Operand::Port(val) => self.hex(val.into(), 2),
+            Operand::Immediate16(val) => self.hex(val, 4),
+            Operand::Address(addr) => name(addr).unwrap_or_else(|| self.hex(addr, 4)),
+            Operand::Restart(num) | Operand::Number(num) => num.to_string(),
+            Operand::Name(text) => self.keyword(text),
+            Operand::Indirect(addr) => format!("({})", self.operand(Operand::Address(addr), name)),
+            Operand::Indexed(index, offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                let offset = u16::from(offset.unsigned_abs());
+                format!("({}{}{})", self.keyword(index), sign, self.hex(offset, 2))
+            }
         }
-        0xd3 => {
-            print!("OUT    #${:02X}", code[1]);
-            opbytes = 2;
+    }
+
+    /// `instr` as text, with the mnemonic padded to the operand column and
+    /// addresses named by `name` when it knows them.
+    fn instruction(&self, instr: &Instruction, name: &dyn Fn(u16) -> Option<String>) -> String {
+        let (mnemonic, parts) = match (self.syntax, instr.syntax) {
+            (Syntax::Intel, Syntax::Intel) => (
+                instr.mnemonic,
+                instr.operands.iter().map(|&op| Part::Operand(op)).collect(),
+            ),
+            _ => zilog(instr),
+        };
+        let operands: Vec<_> = parts
+            .into_iter()
+            .map(|part| match part {
+                Part::Text(text) => self.keyword(text),
+                Part::Operand(op) => self.operand(op, name),
+                Part::Indirect(op) => format!("({})", self.operand(op, name)),
+            })
+            .collect();
+        if operands.is_empty() {
+            self.keyword(mnemonic)
+        } else {
+            format!(
+                "{:<width$}{}",
+                self.keyword(mnemonic),
+                operands.join(","),
+                width = MNEMONIC_WIDTH
+            )
         }
-        0xd4 => {
-            print!("CNC    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    }
+}
+
+const ZILOG_REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const ZILOG_CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+
+fn zilog_pair(pair: RegisterPair) -> &'static str {
+    match pair {
+        RegisterPair::B => "BC",
+        RegisterPair::D => "DE",
+        RegisterPair::H => "HL",
+        RegisterPair::SP => "SP",
+        RegisterPair::PSW => "AF",
+    }
+}
+
+/// The Z80 mnemonic and operands for the 8080 instruction `instr`, or the
+/// operands of a Z80 instruction decoded in Zilog syntax.
+fn zilog(instr: &Instruction) -> (&'static str, Vec<Part>) {
+    let mut ops: Vec<_> = instr
+        .operands
+        .iter()
+        .map(|&op| match op {
+            Operand::Register(reg) => Part::Text(ZILOG_REGISTERS[reg as usize]),
+            Operand::Pair(pair) => Part::Text(zilog_pair(pair)),
+            // RST takes the address called
+            Operand::Restart(num) => Part::Operand(Operand::Immediate(num * 8)),
+            op => Part::Operand(op),
+        })
+        .collect();
+    if instr.syntax == Syntax::Zilog {
+        return (instr.mnemonic, ops);
+    }
+    let condition = Part::Text(ZILOG_CONDITIONS[usize::from((instr.opcode >> 3) & 7)]);
+    // Not the undocumented 8085 JNK, JK and RSTV
+    match (instr.flow, instr.opcode & 0xc7) {
+        (Flow::ConditionalJump, 0xc2) => return ("JP", vec![condition, ops.remove(0)]),
+        (Flow::ConditionalCall, 0xc4) => return ("CALL", vec![condition, ops.remove(0)]),
+        (Flow::ConditionalReturn, 0xc0) => return ("RET", vec![condition]),
+        _ => {}
+    }
+    // Operands of the few instructions taking one, as an indirect operand
+    let indirect = |ops: &[Part]| match (ops.first(), instr.operands.first()) {
+        (Some(Part::Text("BC")), _) => Part::Text("(BC)"),
+        (Some(Part::Text("DE")), _) => Part::Text("(DE)"),
+        (_, Some(&op)) => Part::Indirect(op),
+        _ => unreachable!(),
+    };
+    let (a, hl) = (Part::Text("A"), Part::Text("HL"));
+    match instr.mnemonic {
+        "MOV" | "MVI" | "LXI" => ("LD", ops),
+        "STAX" | "SHLD" | "STA" => {
+            let source = if instr.mnemonic == "SHLD" { hl } else { a };
+            ("LD", vec![indirect(&ops), source])
         }
-        0xd5 => print!("PUSH  D"),
-        0xd6 => {
-            print!("SUI    #${:02X}", code[1]);
-            opbytes = 2;
+        "LDAX" | "LHLD" | "LDA" => {
+            let dest = if instr.mnemonic == "LHLD" { hl } else { a };
+            ("LD", vec![dest, indirect(&ops)])
         }
-        0xd7 => print!("RST   2"),
-        0xd8 => print!("RC"),
-        0xd9 => print!("RET"),
-        0xda => {
-            print!("JC     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        "INX" | "INR" => ("INC", ops),
+        "DCX" | "DCR" => ("DEC", ops),
+        "DAD" => ("ADD", vec![hl, ops.remove(0)]),
+        "RLC" => ("RLCA", ops),
+        "RRC" => ("RRCA", ops),
+        "RAL" => ("RLA", ops),
+        "RAR" => ("RRA", ops),
+        "CMA" => ("CPL", ops),
+        "STC" => ("SCF", ops),
+        "CMC" => ("CCF", ops),
+        "HLT" => ("HALT", ops),
+        "ADD" | "ADI" => ("ADD", vec![a, ops.remove(0)]),
+        "ADC" | "ACI" => ("ADC", vec![a, ops.remove(0)]),
+        "SBB" | "SBI" => ("SBC", vec![a, ops.remove(0)]),
+        "SUB" | "SUI" => ("SUB", ops),
+        "ANA" | "ANI" => ("AND", ops),
+        "XRA" | "XRI" => ("XOR", ops),
+        "ORA" | "ORI" => ("OR", ops),
+        "CMP" | "CPI" => ("CP", ops),
+        "JMP" => ("JP", ops),
+        "PCHL" => ("JP", vec![Part::Text("(HL)")]),
+        "XTHL" => ("EX", vec![Part::Text("(SP)"), hl]),
+        "XCHG" => ("EX", vec![Part::Text("DE"), hl]),
+        "SPHL" => ("LD", vec![Part::Text("SP"), hl]),
+        "OUT" => ("OUT", vec![indirect(&ops), a]),
+        "IN" => ("IN", vec![a, indirect(&ops)]),
+        mnemonic => (mnemonic, ops),
+    }
+}
+
+/// An instruction written in a given format, with the names of the
+/// addresses it uses and the comment on its own address.
+pub struct Formatted<'a> {
+    instr: &'a Instruction,
+    format: Format,
+    symbols: &'a Symbols,
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |addr| self.symbols.name(addr).map(str::to_string);
+        f.write_str(&self.format.instruction(self.instr, &name))?;
+        if let Some(comment) = self.symbols.comment(self.instr.addr) {
+            write!(f, " ; {}", comment)?;
         }
-        0xdb => {
-            print!("IN     #${:02X}{:02X}", code[2], code[1]);
-            opbytes = 2;
+        Ok(())
+    }
+}
+
+pub(crate) const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+pub(crate) const ALU_IMMEDIATE: [&str; 8] =
+    ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+pub(crate) const JUMPS: [&str; 8] = ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+pub(crate) const CALLS: [&str; 8] = ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+pub(crate) const RETURNS: [&str; 8] = ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"];
+
+fn register(code: u8) -> Operand {
+    Operand::Register(REGISTERS[usize::from(code & 7)])
+}
+
+/// The register pair in bits 4-5 of `opcode`. `psw` selects PSW over SP,
+/// for PUSH and POP.
+fn pair(opcode: u8, psw: bool) -> Operand {
+    Operand::Pair(match (opcode >> 4) & 3 {
+        0 => RegisterPair::B,
+        1 => RegisterPair::D,
+        2 => RegisterPair::H,
+        _ if psw => RegisterPair::PSW,
+        _ => RegisterPair::SP,
+    })
+}
+
+/// Decodes the instruction at the start of `bytes`, which were read from
+/// `addr`, as executed by the CPU `model`. Operand bytes past the end of
+/// `bytes` read as 0.
+pub fn decode(bytes: &[u8], addr: u16, model: Model) -> Instruction {
+    match bytes.first() {
+        Some(0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xd9 | 0xcb | 0xdd | 0xed | 0xfd)
+            if model == Model::Z80 =>
+        {
+            decode_z80(bytes, addr)
         }
-        0xdc => {
-            print!("CC     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        _ => decode_intel(bytes, addr, model),
+    }
+}
+
+/// Decodes the instructions of the 8080, with the additions of the 8085
+/// for `model`, in Intel syntax.
+fn decode_intel(bytes: &[u8], addr: u16, model: Model) -> Instruction {
+    use Operand::*;
+
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+    let (imm8, imm16) = (byte(1), u16::from_le_bytes([byte(1), byte(2)]));
+    let y = usize::from((opcode >> 3) & 7);
+    let decoded = match model {
+        Model::I8085 => decode_8085(opcode, imm8, imm16),
+        _ => None,
+    };
+    let (mnemonic, operands, flow) = decoded.unwrap_or_else(|| match opcode {
+        _ if opcode & 0xc7 == 0x00 => ("NOP", vec![], Flow::Next),
+        _ if opcode & 0xcf == 0x01 => (
+            "LXI",
+            vec![pair(opcode, false), Immediate16(imm16)],
+            Flow::Next,
+        ),
+        0x02 | 0x12 => ("STAX", vec![pair(opcode, false)], Flow::Next),
+        0x0a | 0x1a => ("LDAX", vec![pair(opcode, false)], Flow::Next),
+        0x22 => ("SHLD", vec![Address(imm16)], Flow::Next),
+        0x2a => ("LHLD", vec![Address(imm16)], Flow::Next),
+        0x32 => ("STA", vec![Address(imm16)], Flow::Next),
+        0x3a => ("LDA", vec![Address(imm16)], Flow::Next),
+        _ if opcode & 0xcf == 0x03 => ("INX", vec![pair(opcode, false)], Flow::Next),
+        _ if opcode & 0xcf == 0x0b => ("DCX", vec![pair(opcode, false)], Flow::Next),
+        _ if opcode & 0xc7 == 0x04 => ("INR", vec![register(opcode >> 3)], Flow::Next),
+        _ if opcode & 0xc7 == 0x05 => ("DCR", vec![register(opcode >> 3)], Flow::Next),
+        _ if opcode & 0xc7 == 0x06 => (
+            "MVI",
+            vec![register(opcode >> 3), Immediate(imm8)],
+            Flow::Next,
+        ),
+        _ if opcode & 0xcf == 0x09 => ("DAD", vec![pair(opcode, false)], Flow::Next),
+        0x07 => ("RLC", vec![], Flow::Next),
+        0x0f => ("RRC", vec![], Flow::Next),
+        0x17 => ("RAL", vec![], Flow::Next),
+        0x1f => ("RAR", vec![], Flow::Next),
+        0x27 => ("DAA", vec![], Flow::Next),
+        0x2f => ("CMA", vec![], Flow::Next),
+        0x37 => ("STC", vec![], Flow::Next),
+        0x3f => ("CMC", vec![], Flow::Next),
+        0x76 => ("HLT", vec![], Flow::Halt),
+        0x40..=0x7f => (
+            "MOV",
+            vec![register(opcode >> 3), register(opcode)],
+            Flow::Next,
+        ),
+        0x80..=0xbf => (ALU[y], vec![register(opcode)], Flow::Next),
+        0xc3 | 0xcb => ("JMP", vec![Address(imm16)], Flow::Jump),
+        0xc9 | 0xd9 => ("RET", vec![], Flow::Return),
+        0xcd | 0xdd | 0xed | 0xfd => ("CALL", vec![Address(imm16)], Flow::Call),
+        0xd3 => ("OUT", vec![Port(imm8)], Flow::Next),
+        0xdb => ("IN", vec![Port(imm8)], Flow::Next),
+        0xe3 => ("XTHL", vec![], Flow::Next),
+        0xe9 => ("PCHL", vec![], Flow::IndirectJump),
+        0xeb => ("XCHG", vec![], Flow::Next),
+        0xf3 => ("DI", vec![], Flow::Next),
+        0xf9 => ("SPHL", vec![], Flow::Next),
+        0xfb => ("EI", vec![], Flow::Next),
+        _ => match opcode & 0x07 {
+            0 => (RETURNS[y], vec![], Flow::ConditionalReturn),
+            1 => ("POP", vec![pair(opcode, true)], Flow::Next),
+            2 => (JUMPS[y], vec![Address(imm16)], Flow::ConditionalJump),
+            4 => (CALLS[y], vec![Address(imm16)], Flow::ConditionalCall),
+            5 => ("PUSH", vec![pair(opcode, true)], Flow::Next),
+            6 => (ALU_IMMEDIATE[y], vec![Immediate(imm8)], Flow::Next),
+            7 => ("RST", vec![Restart(y as u8)], Flow::Restart),
+            _ => unreachable!(),
+        },
+    });
+    let info = &opcodes(model)[usize::from(opcode)];
+    let undocumented = match model {
+        Model::I8080 => {
+            opcode != 0 && opcode & 0xc7 == 0 || matches!(opcode, 0xcb | 0xd9 | 0xdd | 0xed | 0xfd)
         }
-        0xdd => {
-            print!("CALL   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        Model::I8085 => matches!(
+            opcode,
+            0x08 | 0x10 | 0x18 | 0x28 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd
+        ),
+        Model::Z80 => false,
+    };
+    Instruction {
+        addr,
+        opcode,
+        mnemonic,
+        operands,
+        length: info.length,
+        cycles: info.cycles,
+        cycles_taken: info.cycles_taken,
+        flow,
+        syntax: Syntax::Intel,
+        undocumented,
+    }
+}
+
+/// The instructions the 8085 decodes in place of 8080 aliases, as named
+/// by the 8085 community. Intel only documented RIM and SIM.
+fn decode_8085(opcode: u8, imm8: u8, imm16: u16) -> Option<(&'static str, Vec<Operand>, Flow)> {
+    use Operand::*;
+
+    Some(match opcode {
+        0x08 => ("DSUB", vec![], Flow::Next),
+        0x10 => ("ARHL", vec![], Flow::Next),
+        0x18 => ("RDEL", vec![], Flow::Next),
+        0x20 => ("RIM", vec![], Flow::Next),
+        0x28 => ("LDHI", vec![Immediate(imm8)], Flow::Next),
+        0x30 => ("SIM", vec![], Flow::Next),
+        0x38 => ("LDSI", vec![Immediate(imm8)], Flow::Next),
+        // RST 8 on overflow, whose target is not an operand
+        0xcb => ("RSTV", vec![], Flow::ConditionalCall),
+        0xd9 => ("SHLX", vec![], Flow::Next),
+        0xdd => ("JNK", vec![Address(imm16)], Flow::ConditionalJump),
+        0xed => ("LHLX", vec![], Flow::Next),
+        0xfd => ("JK", vec![Address(imm16)], Flow::ConditionalJump),
+        _ => return None,
+    })
+}
+
+const SHIFTS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const BLOCKS: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+/// A Z80 instruction in Zilog syntax, not conditional.
+fn zilog_instruction(
+    addr: u16,
+    opcode: u8,
+    (mnemonic, operands): (&'static str, Vec<Operand>),
+    length: u8,
+    cycles: u8,
+) -> Instruction {
+    Instruction {
+        addr,
+        opcode,
+        mnemonic,
+        operands,
+        length,
+        cycles,
+        cycles_taken: cycles,
+        flow: Flow::Next,
+        syntax: Syntax::Zilog,
+        undocumented: false,
+    }
+}
+
+/// Decodes the instructions only the Z80 has: relative jumps, exchanges
+/// with the alternate registers and prefixed instructions.
+fn decode_z80(bytes: &[u8], addr: u16) -> Instruction {
+    use Operand::*;
+
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+    let info = &opcodes(Model::Z80)[usize::from(opcode)];
+    let target = addr.wrapping_add(2).wrapping_add(byte(1) as i8 as u16);
+    let (text, flow) = match opcode {
+        0x08 => (("EX", vec![Name("AF"), Name("AF'")]), Flow::Next),
+        0x10 => (("DJNZ", vec![Address(target)]), Flow::ConditionalJump),
+        0x18 => (("JR", vec![Address(target)]), Flow::Jump),
+        0x20 | 0x28 | 0x30 | 0x38 => {
+            let condition = Name(ZILOG_CONDITIONS[usize::from((opcode >> 3) & 3)]);
+            (
+                ("JR", vec![condition, Address(target)]),
+                Flow::ConditionalJump,
+            )
         }
-        0xde => {
-            print!("SBI    #${:02X}", code[1]);
-            opbytes = 2;
+        0xd9 => (("EXX", vec![]), Flow::Next),
+        0xcb => return decode_cb(addr, byte(1)),
+        0xed => return decode_ed(addr, byte(1), u16::from_le_bytes([byte(2), byte(3)])),
+        _ => {
+            let index = if opcode == 0xdd { "IX" } else { "IY" };
+            return decode_index(&bytes[1..], addr, index);
         }
-        0xdf => print!("RST   3"),
+    };
+    Instruction {
+        cycles_taken: info.cycles_taken,
+        flow,
+        ..zilog_instruction(addr, opcode, text, info.length, info.cycles)
+    }
+}
 
-        0xe0 => print!("RPO"),
-        0xe1 => print!("POP   H"),
-        0xe2 => {
-            print!("JPO    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
-        }
-        0xe3 => print!("XTHL"),
-        0xe4 => {
-            print!("CPO    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+/// The rotation, shift or bit instruction `op` following a CB prefix,
+/// applied to `target`.
+fn bit_instruction(op: u8, target: Operand) -> (&'static str, Vec<Operand>) {
+    let (kind, bit) = (usize::from((op >> 3) & 7), Operand::Number((op >> 3) & 7));
+    match op >> 6 {
+        0 => (SHIFTS[kind], vec![target]),
+        1 => ("BIT", vec![bit, target]),
+        2 => ("RES", vec![bit, target]),
+        _ => ("SET", vec![bit, target]),
+    }
+}
+
+fn decode_cb(addr: u16, op: u8) -> Instruction {
+    let cycles = match (op >> 6, op & 7) {
+        (1, 6) => 12,
+        (_, 6) => 15,
+        _ => 8,
+    };
+    Instruction {
+        undocumented: op & 0xf8 == 0x30,
+        ..zilog_instruction(addr, 0xcb, bit_instruction(op, register(op)), 2, cycles)
+    }
+}
+
+fn decode_ed(addr: u16, op: u8, imm16: u16) -> Instruction {
+    use Operand::*;
+
+    let reg = (op >> 3) & 7;
+    let pair = pair(op, false);
+    let (text, length, cycles) = match op {
+        0x70 => (("IN", vec![Name("(C)")]), 2, 12),
+        0x71 => (("OUT", vec![Name("(C)"), Immediate(0)]), 2, 12),
+        _ if op & 0xc7 == 0x40 => (("IN", vec![register(reg), Name("(C)")]), 2, 12),
+        _ if op & 0xc7 == 0x41 => (("OUT", vec![Name("(C)"), register(reg)]), 2, 12),
+        _ if op & 0xcf == 0x42 => (("SBC", vec![Name("HL"), pair]), 2, 15),
+        _ if op & 0xcf == 0x4a => (("ADC", vec![Name("HL"), pair]), 2, 15),
+        _ if op & 0xcf == 0x43 => (("LD", vec![Indirect(imm16), pair]), 4, 20),
+        _ if op & 0xcf == 0x4b => (("LD", vec![pair, Indirect(imm16)]), 4, 20),
+        _ if op & 0xc7 == 0x44 => (("NEG", vec![]), 2, 8),
+        0x4d => (("RETI", vec![]), 2, 14),
+        _ if op & 0xc7 == 0x45 => (("RETN", vec![]), 2, 14),
+        _ if op & 0xc7 == 0x46 => {
+            let mode = [0, 0, 1, 2][usize::from(reg & 3)];
+            (("IM", vec![Number(mode)]), 2, 8)
         }
-        0xe5 => print!("PUSH  H"),
-        0xe6 => {
-            print!("ANI    #${:02X}", code[1]);
-            opbytes = 2;
+        0x47 => (("LD", vec![Name("I"), Name("A")]), 2, 9),
+        0x4f => (("LD", vec![Name("R"), Name("A")]), 2, 9),
+        0x57 => (("LD", vec![Name("A"), Name("I")]), 2, 9),
+        0x5f => (("LD", vec![Name("A"), Name("R")]), 2, 9),
+        0x67 => (("RRD", vec![]), 2, 18),
+        0x6f => (("RLD", vec![]), 2, 18),
+        0xa0..=0xa3 | 0xa8..=0xab | 0xb0..=0xb3 | 0xb8..=0xbb => {
+            let mnemonic = BLOCKS[usize::from((op >> 3) & 3)][usize::from(op & 3)];
+            ((mnemonic, vec![]), 2, 16)
         }
-        0xe7 => print!("RST   4"),
-        0xe8 => print!("RPE"),
-        0xe9 => print!("PCHL"),
-        0xea => {
-            print!("JPE    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        // Undefined, executed as two NOPs
+        _ => (("NOP", vec![]), 2, 8),
+    };
+    // Aliases of NEG, RETN and IM, IN and OUT with the (HL) encoding, and a
+    // second LD (nn),HL and LD HL,(nn)
+    let undocumented = match op {
+        0x44 | 0x45 | 0x4d | 0x46 | 0x56 | 0x5e => false,
+        0x63 | 0x6b | 0x70 | 0x71 => true,
+        0x40..=0x7f => matches!(op & 7, 4..=6) || text.0 == "NOP",
+        _ => text.0 == "NOP",
+    };
+    let mut instr = zilog_instruction(addr, 0xed, text, length, cycles);
+    instr.undocumented = undocumented;
+    match op {
+        0x45 | 0x4d | 0x55 | 0x5d | 0x65 | 0x6d | 0x75 | 0x7d => instr.flow = Flow::Return,
+        0xb0..=0xb3 | 0xb8..=0xbb => instr.cycles_taken = 21,
+        _ => {}
+    }
+    instr
+}
+
+/// The instruction following a DD or FD prefix, which uses `index` in
+/// place of HL, or of the memory at HL plus a displacement. The prefix
+/// alone is decoded as an undocumented NOP when the instruction does not
+/// use HL.
+fn decode_index(bytes: &[u8], addr: u16, index: &'static str) -> Instruction {
+    use Operand::*;
+
+    let prefix = if index == "IX" { 0xdd } else { 0xfd };
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let (op, offset) = (byte(0), byte(1) as i8);
+    if op == 0xcb {
+        let op = byte(2);
+        let (mnemonic, mut operands) = bit_instruction(op, Indexed(index, offset));
+        // Also copied to a register, undocumented
+        let undocumented = op & 7 != 6;
+        if undocumented && op >> 6 != 1 {
+            operands.push(register(op));
         }
-        0xeb => print!("XCHG"),
-        0xec => {
-            print!("CPE    ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        let cycles = if op >> 6 == 1 { 20 } else { 23 };
+        return Instruction {
+            undocumented,
+            ..zilog_instruction(addr, prefix, (mnemonic, operands), 4, cycles)
+        };
+    }
+
+    // The Zilog form of the unprefixed instruction, with HL replaced
+    let memory = uses_memory_at_hl(op);
+    let base = if memory {
+        // The displacement comes before any immediate operand
+        decode_intel(&[op, byte(2)], addr, Model::Z80)
+    } else {
+        decode_intel(bytes, addr, Model::Z80)
+    };
+    let (mnemonic, parts) = zilog(&base);
+    let mut replaced = false;
+    let mut undocumented = false;
+    let operands: Vec<_> = parts
+        .into_iter()
+        .map(|part| {
+            let text = match part {
+                Part::Text(text) => text,
+                Part::Operand(op) => return op,
+                Part::Indirect(Address(addr)) => return Indirect(addr),
+                Part::Indirect(op) => return op,
+            };
+            let operand = match (text, memory) {
+                ("(HL)", true) => Indexed(index, offset),
+                (_, true) => return Name(text),
+                ("HL", _) => Name(index),
+                ("(HL)", _) => Name(if index == "IX" { "(IX)" } else { "(IY)" }),
+                ("H", _) | ("L", _) => {
+                    undocumented = true;
+                    match (index, text) {
+                        ("IX", "H") => Name("IXH"),
+                        ("IX", _) => Name("IXL"),
+                        (_, "H") => Name("IYH"),
+                        _ => Name("IYL"),
+                    }
+                }
+                _ => return Name(text),
+            };
+            replaced = true;
+            operand
+        })
+        .collect();
+    // EX DE,HL is not affected by the prefix
+    if !replaced || op == 0xeb {
+        return Instruction {
+            undocumented: true,
+            ..zilog_instruction(addr, prefix, ("NOP", vec![]), 1, 4)
+        };
+    }
+    let (length, cycles) = match op {
+        0x34 | 0x35 => (base.length + 1, 23),
+        _ if memory => (base.length + 1, 19),
+        _ => (base.length, base.cycles + 4),
+    };
+    Instruction {
+        flow: base.flow,
+        undocumented,
+        ..zilog_instruction(addr, prefix, (mnemonic, operands), length + 1, cycles)
+    }
+}
+
+pub fn disassemble8080_op(codebuffer: &[u8], pc: usize) -> usize {
+    disassemble8080_op_at(&codebuffer[pc..], pc)
+}
+
+/// Prints the instruction at the start of `code`, which was read from
+/// address `pc`, and returns its length.
+pub fn disassemble8080_op_at(code: &[u8], pc: usize) -> usize {
+    let instr = decode(code, pc as u16, Model::I8080);
+    println!("{:04X} {:02X} {}", pc, instr.opcode, instr);
+    usize::from(instr.length)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteKind {
+    Data,
+    Opcode,
+    Operand,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Label {
+    /// A jump target, `L_xxxx`.
+    Local,
+    /// A call or RST target, `SUB_xxxx`.
+    Subroutine,
+}
+
+/// Data bytes per DB line of a listing.
+const DB_PER_LINE: usize = 8;
+
+/// A program image split into code and data, by following jumps and calls
+/// from a set of entry points.
+///
+/// Code is only found where control can be shown to reach it: targets of
+/// PCHL and bytes that would decode as undocumented opcodes are left as
+/// data. The listing it displays of 8080 code reassembles to the original
+/// image.
+pub struct Disassembly<'a> {
+    image: &'a [u8],
+    origin: u16,
+    model: Model,
+    kinds: Vec<ByteKind>,
+    labels: BTreeMap<u16, Label>,
+    symbols: &'a Symbols,
+    /// Syntax and number format of the listing.
+    pub format: Format,
+}
+
+impl<'a> Disassembly<'a> {
+    /// Disassembles `image`, loaded at `origin`, starting from `entries`.
+    pub fn new(image: &'a [u8], origin: u16, entries: &[u16]) -> Self {
+        Disassembly::with_symbols(image, origin, entries, &NO_SYMBOLS)
+    }
+
+    /// Same as `new`, but names addresses after `symbols` instead of
+    /// generated labels, and shows their comments.
+    pub fn with_symbols(
+        image: &'a [u8],
+        origin: u16,
+        entries: &[u16],
+        symbols: &'a Symbols,
+    ) -> Self {
+        Disassembly::with_model(image, origin, entries, symbols, Model::I8080)
+    }
+
+    /// Same as `with_symbols`, decoding the instructions of the CPU
+    /// `model`.
+    pub fn with_model(
+        image: &'a [u8],
+        origin: u16,
+        entries: &[u16],
+        symbols: &'a Symbols,
+        model: Model,
+    ) -> Self {
+        let mut dis = Disassembly {
+            image,
+            origin,
+            model,
+            kinds: vec![ByteKind::Data; image.len()],
+            labels: BTreeMap::new(),
+            symbols,
+            format: Format::default(),
+        };
+        let mut pending = Vec::new();
+        for &entry in entries {
+            dis.add_label(entry, Label::Local);
+            pending.push(entry);
         }
-        0xed => {
-            print!("CALL   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        while let Some(addr) = pending.pop() {
+            dis.trace(addr, &mut pending);
         }
-        0xee => {
-            print!("XRI    #${:02X}", code[1]);
-            opbytes = 2;
+        // Labels can't be placed inside an instruction, such targets are
+        // referenced by address instead
+        let kinds = &dis.kinds;
+        dis.labels
+            .retain(|&addr, _| kinds[usize::from(addr - origin)] != ByteKind::Operand);
+        dis
+    }
+
+    /// Offset of `addr` in the image, if it is inside it.
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = usize::from(addr.checked_sub(self.origin)?);
+        if offset < self.image.len() {
+            Some(offset)
+        } else {
+            None
         }
-        0xef => print!("RST   5"),
+    }
 
-        0xf0 => print!("RP"),
-        0xf1 => print!("POP   PSW"),
-        0xf2 => {
-            print!("JP     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    fn add_label(&mut self, addr: u16, label: Label) {
+        if self.offset(addr).is_some() {
+            let entry = self.labels.entry(addr).or_insert(label);
+            if label == Label::Subroutine {
+                *entry = label;
+            }
         }
-        0xf3 => print!("DI"),
-        0xf4 => {
-            print!("CP     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    }
+
+    /// Decodes instructions from `addr` until control leaves the sequence,
+    /// queuing the targets of jumps and calls in `pending`.
+    fn trace(&mut self, mut addr: u16, pending: &mut Vec<u16>) {
+        while let Some(offset) = self.offset(addr) {
+            if self.kinds[offset] != ByteKind::Data {
+                return;
+            }
+            let instr = decode(&self.image[offset..], addr, self.model);
+            let end = offset + usize::from(instr.length);
+            if instr.is_undocumented()
+                || end > self.image.len()
+                || self.kinds[offset..end].iter().any(|&k| k != ByteKind::Data)
+            {
+                return;
+            }
+            self.kinds[offset] = ByteKind::Opcode;
+            for kind in &mut self.kinds[offset + 1..end] {
+                *kind = ByteKind::Operand;
+            }
+            if let Some(target) = instr.target() {
+                let label = match instr.flow {
+                    Flow::Call | Flow::ConditionalCall | Flow::Restart => Label::Subroutine,
+                    _ => Label::Local,
+                };
+                self.add_label(target, label);
+                pending.push(target);
+            }
+            match instr.flow {
+                Flow::Jump | Flow::Return | Flow::IndirectJump => return,
+                _ => addr = addr.wrapping_add(u16::from(instr.length)),
+            }
         }
-        0xf5 => print!("PUSH  PSW"),
-        0xf6 => {
-            print!("ORI    #${:02X}", code[1]);
-            opbytes = 2;
+    }
+
+    /// Whether `addr` is the start of an instruction found to be code.
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.offset(addr)
+            .is_some_and(|offset| self.kinds[offset] == ByteKind::Opcode)
+    }
+
+    /// The name of `addr`: its symbol, or the label generated for a jump
+    /// or call target.
+    pub fn label(&self, addr: u16) -> Option<String> {
+        if let Some(name) = self.symbols.name(addr) {
+            return Some(name.to_string());
         }
-        0xf7 => print!("RST   6"),
-        0xf8 => print!("RM"),
-        0xf9 => print!("SPHL"),
-        0xfa => {
-            print!("JM     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        self.labels.get(&addr).map(|label| match label {
+            Label::Local => format!("L_{:04X}", addr),
+            Label::Subroutine => format!("SUB_{:04X}", addr),
+        })
+    }
+
+    /// Whether a label for `addr` can be placed in the listing, instead of
+    /// being defined with EQU.
+    fn in_listing(&self, addr: u16) -> bool {
+        self.offset(addr)
+            .is_some_and(|offset| self.kinds[offset] != ByteKind::Operand)
+    }
+
+    /// Whether a DB line must end before `addr`, to show its label or
+    /// comment.
+    fn starts_line(&self, addr: u16) -> bool {
+        self.label(addr).is_some() || self.symbols.comment(addr).is_some()
+    }
+
+    /// The address comment ending each line of the listing.
+    fn write_comment(&self, f: &mut fmt::Formatter, addr: u16) -> fmt::Result {
+        match self.symbols.comment(addr) {
+            Some(comment) => writeln!(f, "; {:04X} {}", addr, comment),
+            None => writeln!(f, "; {:04X}", addr),
         }
-        0xfb => print!("EI"),
-        0xfc => {
-            print!("CM     ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+    }
+
+    fn write_instruction(&self, f: &mut fmt::Formatter, instr: &Instruction) -> fmt::Result {
+        let name = |addr| self.label(addr);
+        let text = self.format.instruction(instr, &name);
+        write!(f, "        {:<31} ", text)?;
+        self.write_comment(f, instr.addr)
+    }
+
+    fn write_data(&self, f: &mut fmt::Formatter, addr: u16, bytes: &[u8]) -> fmt::Result {
+        let bytes: Vec<_> = bytes
+            .iter()
+            .map(|&b| self.format.hex(b.into(), 2))
+            .collect();
+        let directive = self.format.keyword("DB");
+        write!(f, "        {:<8}{:<23} ", directive, bytes.join(","))?;
+        self.write_comment(f, addr)
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, name) in self.symbols.names() {
+            if !self.in_listing(addr) {
+                let (equ, value) = (self.format.keyword("EQU"), self.format.hex(addr, 4));
+                writeln!(f, "{:<7} {:<8}{}", name, equ, value)?;
+            }
         }
-        0xfd => {
-            print!("CALL   ${:02X}{:02X}", code[2], code[1]);
-            opbytes = 3;
+        let (org, origin) = (self.format.keyword("ORG"), self.format.hex(self.origin, 4));
+        writeln!(f, "        {:<8}{}", org, origin)?;
+        let mut offset = 0;
+        while offset < self.image.len() {
+            let addr = self.origin.wrapping_add(offset as u16);
+            if let Some(label) = self.label(addr) {
+                writeln!(f, "{}:", label)?;
+            }
+            if self.kinds[offset] == ByteKind::Opcode {
+                let instr = decode(&self.image[offset..], addr, self.model);
+                self.write_instruction(f, &instr)?;
+                offset += usize::from(instr.length);
+                continue;
+            }
+            // Data runs until the next code, label or comment
+            let mut end = offset + 1;
+            while end < self.image.len()
+                && end - offset < DB_PER_LINE
+                && self.kinds[end] == ByteKind::Data
+                && !self.starts_line(self.origin.wrapping_add(end as u16))
+            {
+                end += 1;
+            }
+            self.write_data(f, addr, &self.image[offset..end])?;
+            offset = end;
         }
-        0xfe => {
-            print!("CPI    #${:02X}", code[1]);
-            opbytes = 2;
+        Ok(())
+    }
+}
+
+#[test]
+fn decode_operands() {
+    let instr = decode(&[0x01, 0x34, 0x12], 0x100, Model::I8080);
+    assert_eq!(instr.mnemonic, "LXI");
+    assert_eq!(
+        instr.operands,
+        vec![Operand::Pair(RegisterPair::B), Operand::Immediate16(0x1234)]
+    );
+    assert_eq!((instr.length, instr.cycles), (3, 10));
+    assert_eq!(instr.to_string(), "LXI     B,1234h");
+
+    assert_eq!(decode(&[0x0b], 0, Model::I8080).to_string(), "DCX     B");
+    assert_eq!(decode(&[0x7e], 0, Model::I8080).to_string(), "MOV     A,M");
+    assert_eq!(
+        decode(&[0xdb, 0x02], 0, Model::I8080).to_string(),
+        "IN      02h"
+    );
+    assert_eq!(decode(&[0xf5], 0, Model::I8080).to_string(), "PUSH    PSW");
+    assert_eq!(decode(&[0xef], 0, Model::I8080).to_string(), "RST     5");
+    assert_eq!(decode(&[0x2f], 0, Model::I8080).to_string(), "CMA");
+}
+
+#[test]
+fn decode_flow() {
+    let instr = decode(&[0xc4, 0x00, 0x20], 0, Model::I8080);
+    assert_eq!(instr.flow, Flow::ConditionalCall);
+    assert_eq!(instr.target(), Some(0x2000));
+    assert_eq!((instr.cycles, instr.cycles_taken), (11, 17));
+
+    let instr = decode(&[0xd7], 0, Model::I8080);
+    assert_eq!((instr.flow, instr.target()), (Flow::Restart, Some(0x10)));
+    assert_eq!(decode(&[0xe9], 0, Model::I8080).target(), None);
+    assert!(decode(&[0xdd, 0x00, 0x00], 0, Model::I8080).is_undocumented());
+    assert!(!decode(&[0x00], 0, Model::I8080).is_undocumented());
+}
+
+#[test]
+fn decode_all_lengths() {
+    for &model in &[Model::I8080, Model::I8085] {
+        for opcode in 0..=255u8 {
+            let instr = decode(&[opcode], 0, model);
+            let expected = match &instr.operands[..] {
+                [.., Operand::Immediate16(_)] | [.., Operand::Address(_)] => 3,
+                [.., Operand::Immediate(_)] | [.., Operand::Port(_)] => 2,
+                _ => 1,
+            };
+            assert_eq!(instr.length, expected, "Wrong length for {:02X}", opcode);
         }
-        0xff => print!("RST   7"),
     }
+}
+
+#[test]
+fn decode_8085_instructions() {
+    let decode = |bytes: &[u8]| decode(bytes, 0, Model::I8085);
+    assert_eq!(decode(&[0x20]).to_string(), "RIM");
+    assert_eq!(decode(&[0x30]).to_string(), "SIM");
+    assert!(!decode(&[0x30]).is_undocumented());
+    let instr = decode(&[0x28, 0x05]);
+    assert_eq!(instr.to_string(), "LDHI    05h");
+    assert_eq!((instr.length, instr.cycles), (2, 10));
+    assert!(instr.is_undocumented());
+    let instr = decode(&[0xfd, 0x00, 0x20]);
+    assert_eq!(instr.to_string(), "JK      2000h");
+    assert_eq!(
+        (instr.flow, instr.target()),
+        (Flow::ConditionalJump, Some(0x2000))
+    );
+    assert_eq!((instr.cycles, instr.cycles_taken), (7, 10));
+    assert_eq!(decode(&[0xc5]).cycles, 12);
+}
+
+#[test]
+fn decode_z80_instructions() {
+    let zilog = Format {
+        syntax: Syntax::Zilog,
+        ..Format::default()
+    };
+    let expected = [
+        (&[0x10, 0xfe][..], "DJNZ    0100h", 2, 8),
+        (&[0x38, 0x10], "JR      C,0112h", 2, 7),
+        (&[0x08], "EX      AF,AF'", 1, 4),
+        (&[0xd9], "EXX", 1, 4),
+        (&[0x7e], "LD      A,(HL)", 1, 7),
+        (&[0xcb, 0x11], "RL      C", 2, 8),
+        (&[0xcb, 0x7e], "BIT     7,(HL)", 2, 12),
+        (&[0xed, 0x43, 0x34, 0x12], "LD      (1234h),BC", 4, 20),
+        (&[0xed, 0x78], "IN      A,(C)", 2, 12),
+        (&[0xed, 0x5e], "IM      2", 2, 8),
+        (&[0xed, 0xb0], "LDIR", 2, 16),
+        (&[0xdd, 0x21, 0x34, 0x12], "LD      IX,1234h", 4, 14),
+        (&[0xdd, 0x7e, 0xfb], "LD      A,(IX-05h)", 3, 19),
+        (&[0xfd, 0x36, 0x02, 0x10], "LD      (IY+02h),10h", 4, 19),
+        (&[0xfd, 0x35, 0x02], "DEC     (IY+02h)", 3, 23),
+        (&[0xdd, 0x29], "ADD     IX,IX", 2, 15),
+        (&[0xdd, 0xe3], "EX      (SP),IX", 2, 23),
+        (&[0xdd, 0xcb, 0x03, 0xc6], "SET     0,(IX+03h)", 4, 23),
+    ];
+    for &(bytes, text, length, cycles) in &expected {
+        let instr = decode(bytes, 0x100, Model::Z80);
+        assert_eq!(instr.display(zilog, &NO_SYMBOLS).to_string(), text);
+        assert_eq!((instr.length, instr.cycles), (length, cycles), "{}", text);
+        assert!(!instr.is_undocumented(), "{}", text);
+    }
+
+    // Written in Zilog syntax whatever the format
+    let instr = decode(&[0x20, 0xfe], 0x100, Model::Z80);
+    assert_eq!(instr.to_string(), "JR      NZ,0100h");
+    assert_eq!(
+        (instr.flow, instr.target()),
+        (Flow::ConditionalJump, Some(0x100))
+    );
+    assert_eq!((instr.cycles, instr.cycles_taken), (7, 12));
+    let instr = decode(&[0xdd, 0xe9], 0, Model::Z80);
+    assert_eq!(
+        (instr.to_string().as_str(), instr.flow),
+        ("JP      (IX)", Flow::IndirectJump)
+    );
+    assert_eq!(decode(&[0xed, 0x4d], 0, Model::Z80).flow, Flow::Return);
+    assert_eq!(decode(&[0xed, 0xb1], 0, Model::Z80).cycles_taken, 21);
+
+    let undocumented = decode(&[0xdd, 0x7c], 0, Model::Z80);
+    assert_eq!(undocumented.to_string(), "LD      A,IXH");
+    assert!(undocumented.is_undocumented());
+    // The prefix of an instruction not using HL is skipped
+    let prefix = decode(&[0xdd, 0x00], 0, Model::Z80);
+    assert_eq!((prefix.mnemonic, prefix.length), ("NOP", 1));
+    assert!(prefix.is_undocumented());
+    assert!(decode(&[0xcb, 0x30], 0, Model::Z80).is_undocumented());
+    assert!(decode(&[0xed, 0x4c], 0, Model::Z80).is_undocumented());
+}
 
-    println!();
+#[test]
+fn disassembly_listing() {
+    #[rustfmt::skip]
+    let image = [
+        0xc3, 0x06, 0x00, // JMP L_0006
+        0x12, 0x34, 0xab, // data
+        0xcd, 0x0d, 0x00, // CALL SUB_000D
+        0xc2, 0x06, 0x00, // JNZ L_0006
+        0x76,             // HLT
+        0x3e, 0xff,       // MVI A,0FFh
+        0xc9,             // RET
+        0xaf,             // data
+    ];
+    let dis = Disassembly::new(&image, 0, &[0]);
+    assert!(dis.is_code(0x09) && !dis.is_code(0x03) && !dis.is_code(0x10));
+    assert_eq!(dis.label(0x0d).as_deref(), Some("SUB_000D"));
+    assert_eq!(
+        dis.to_string(),
+        "        ORG     0000h
+L_0000:
+        JMP     L_0006                  ; 0000
+        DB      12h,34h,0ABh            ; 0003
+L_0006:
+        CALL    SUB_000D                ; 0006
+        JNZ     L_0006                  ; 0009
+        HLT                             ; 000C
+SUB_000D:
+        MVI     A,0FFh                  ; 000D
+        RET                             ; 000F
+        DB      0AFh                    ; 0010
+"
+    );
+}
+
+#[test]
+fn disassembly_symbols() {
+    let symbols = Symbols::parse(
+        "0005 Draw ; Draws something
+0002 Patch
+20F8 Score
+0009 ; Table",
+    )
+    .unwrap();
+    #[rustfmt::skip]
+    let image = [
+        0xcd, 0x05, 0x00, // CALL Draw
+        0xc9,             // RET
+        0x00,             // data
+        0x32, 0xf8, 0x20, // STA Score
+        0xc9,             // RET
+        0x01,             // data
+    ];
+    let dis = Disassembly::with_symbols(&image, 0, &[0], &symbols);
+    assert_eq!(
+        dis.to_string(),
+        "Patch   EQU     0002h
+Score   EQU     20F8h
+        ORG     0000h
+L_0000:
+        CALL    Draw                    ; 0000
+        RET                             ; 0003
+        DB      00h                     ; 0004
+Draw:
+        STA     Score                   ; 0005 Draws something
+        RET                             ; 0008
+        DB      01h                     ; 0009 Table
+"
+    );
+    let instr = decode(&image[5..], 5, Model::I8080);
+    assert_eq!(
+        instr.display(Format::default(), &symbols).to_string(),
+        "STA     Score ; Draws something"
+    );
+}
+
+#[test]
+fn formats() {
+    let zilog = Format {
+        syntax: Syntax::Zilog,
+        ..Format::default()
+    };
+    let expected = [
+        (&[0x7e][..], "LD      A,(HL)"),
+        (&[0x0a], "LD      A,(BC)"),
+        (&[0x22, 0x34, 0x12], "LD      (1234h),HL"),
+        (&[0x32, 0xff, 0x20], "LD      (20FFh),A"),
+        (&[0x09], "ADD     HL,BC"),
+        (&[0x98], "SBC     A,B"),
+        (&[0xfe, 0x10], "CP      10h"),
+        (&[0xca, 0x00, 0x01], "JP      Z,0100h"),
+        (&[0xe0], "RET     PO"),
+        (&[0xe9], "JP      (HL)"),
+        (&[0xeb], "EX      DE,HL"),
+        (&[0xd3, 0x03], "OUT     (03h),A"),
+        (&[0xf1], "POP     AF"),
+        (&[0xff], "RST     38h"),
+        (&[0x76], "HALT"),
+    ];
+    for &(bytes, text) in &expected {
+        let instr = decode(bytes, 0, Model::I8080);
+        assert_eq!(instr.display(zilog, &NO_SYMBOLS).to_string(), text);
+    }
 
-    opbytes
+    let instr = decode(&[0x3e, 0xfa], 0, Model::I8080);
+    let text = |hex, lowercase| {
+        let format = Format {
+            hex,
+            lowercase,
+            ..Format::default()
+        };
+        instr.display(format, &NO_SYMBOLS).to_string()
+    };
+    assert_eq!(text(Hex::Suffix, false), "MVI     A,0FAh");
+    assert_eq!(text(Hex::Dollar, false), "MVI     A,$FA");
+    assert_eq!(text(Hex::Prefix, true), "mvi     a,0xfa");
 }
@@ -0,0 +1,75 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::state::RomWrite;
+
+/// Reasons `Emu8080::try_step` can stop instead of executing normally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepError {
+    /// A push or pop carried the stack pointer across the top or bottom of
+    /// the address space, with `Emu8080::strict_stack` set. The access
+    /// wrapped around as on hardware.
+    StackWraparound { sp: usize },
+    /// The program counter points outside of mapped memory.
+    PcOutOfMemory { pc: usize },
+    /// The CPU is halted on the HLT instruction at `pc` with interrupts
+    /// disabled, so it can never resume.
+    Halted { pc: usize },
+    /// The instruction wrote to a ROM region with the `Error` policy.
+    RomWrite(RomWrite),
+    /// The undocumented `opcode` at `pc` was fetched in strict mode. It was
+    /// not executed.
+    IllegalOpcode { opcode: u8, pc: usize },
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::StackWraparound { sp } => {
+                write!(f, "Stack pointer wrapped around memory at {:04X}", sp)
+            }
+            StepError::PcOutOfMemory { pc } => {
+                write!(f, "Program counter out of mapped memory: {:04X}", pc)
+            }
+            StepError::Halted { pc } => write!(f, "CPU halted at {:04X}", pc),
+            StepError::RomWrite(write) => write.fmt(f),
+            StepError::IllegalOpcode { opcode, pc } => {
+                write!(f, "Illegal opcode {:02X} at {:04X}", opcode, pc)
+            }
+        }
+    }
+}
+
+impl Error for StepError {}
+
+/// A line of a symbol file that could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolError {
+    /// Line number, starting at 1.
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid symbol on line {}: {}", self.line, self.text)
+    }
+}
+
+impl Error for SymbolError {}
+
+/// An error in assembly source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// Line number, starting at 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
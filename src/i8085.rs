@@ -0,0 +1,242 @@
+//! The Intel 8085: RIM and SIM, the extra interrupt inputs and the
+//! undocumented instructions.
+
+use crate::interrupt::InterruptSource;
+use crate::memory::MemoryBus;
+use crate::{Emu8080, InOutHandler, Instruction};
+
+/// Addresses jumped to when TRAP and RST 5.5 to 7.5 are accepted.
+pub const TRAP_VECTOR: u16 = 0x24;
+pub const RST55_VECTOR: u16 = 0x2c;
+pub const RST65_VECTOR: u16 = 0x34;
+pub const RST75_VECTOR: u16 = 0x3c;
+
+/// Address called by RSTV when the V flag is set.
+const RSTV_VECTOR: u16 = 0x40;
+
+/// The 8085's interrupt inputs besides INTR, and their masks.
+///
+/// TRAP is accepted at the next instruction boundary whatever the interrupt
+/// enable flag. RST 7.5, then 6.5, then 5.5 are accepted like INTR, with
+/// priority over it, unless masked by SIM.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Interrupts8085 {
+    /// Level of the RST 5.5 input.
+    pub rst55: bool,
+    /// Level of the RST 6.5 input.
+    pub rst65: bool,
+    /// The RST 7.5 flip-flop, set on a rising edge of the input.
+    rst75: bool,
+    /// Set by a TRAP pulse until it is accepted.
+    trap: bool,
+    /// M7.5, M6.5 and M5.5, in the bit order of SIM and RIM.
+    mask: u8,
+    /// Interrupt enable flag when the last TRAP was accepted, reported by
+    /// the next RIM.
+    ie_before_trap: Option<bool>,
+    /// Level of the SOD output.
+    sod: bool,
+}
+
+impl Interrupts8085 {
+    /// Pulses the TRAP input.
+    pub fn trap(&mut self) {
+        self.trap = true;
+    }
+
+    /// Raises the RST 7.5 input, setting its flip-flop.
+    pub fn rst75(&mut self) {
+        self.rst75 = true;
+    }
+
+    /// The interrupt mask set by SIM: bit 2 masks RST 7.5, bit 1 RST 6.5
+    /// and bit 0 RST 5.5. All are unmasked on reset.
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    /// Level of the SOD output, as last set by SIM.
+    pub fn sod(&self) -> bool {
+        self.sod
+    }
+
+    /// Takes the highest priority interrupt to accept, returning its
+    /// vector. Only TRAP is accepted unless `enabled`.
+    pub(crate) fn accept(&mut self, enabled: bool) -> Option<u16> {
+        if self.trap {
+            self.trap = false;
+            Some(TRAP_VECTOR)
+        } else if !enabled {
+            None
+        } else if self.rst75 && self.mask & 0x04 == 0 {
+            self.rst75 = false;
+            Some(RST75_VECTOR)
+        } else if self.rst65 && self.mask & 0x02 == 0 {
+            Some(RST65_VECTOR)
+        } else if self.rst55 && self.mask & 0x01 == 0 {
+            Some(RST55_VECTOR)
+        } else {
+            None
+        }
+    }
+
+    /// The value loaded by RIM: SID, the pending RST 7.5 to 5.5 inputs,
+    /// the interrupt enable flag and the mask.
+    fn rim(&mut self, int_enable: bool, sid: bool) -> u8 {
+        let ie = self.ie_before_trap.take().unwrap_or(int_enable);
+        (u8::from(sid) << 7)
+            | (u8::from(self.rst75) << 6)
+            | (u8::from(self.rst65) << 5)
+            | (u8::from(self.rst55) << 4)
+            | (u8::from(ie) << 3)
+            | self.mask
+    }
+
+    /// Applies SIM with `a`. Returns the new level of SOD if it is
+    /// enabled.
+    fn sim(&mut self, a: u8) -> Option<bool> {
+        if a & 0x08 != 0 {
+            self.mask = a & 0x07;
+        }
+        if a & 0x10 != 0 {
+            self.rst75 = false;
+        }
+        if a & 0x40 != 0 {
+            self.sod = a & 0x80 != 0;
+            Some(self.sod)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: InOutHandler, M: MemoryBus, I: InterruptSource> Emu8080<T, M, I> {
+    /// Accepts TRAP or one of the RST inputs, returning the cycles taken.
+    pub(crate) fn sample_8085(&mut self) -> Option<usize> {
+        let enabled = self.int_enable && !self.int_delay;
+        let vector = self.state.i8085.accept(enabled)?;
+        if vector == TRAP_VECTOR {
+            let ie = self.int_enable;
+            self.state.i8085.ie_before_trap = Some(ie);
+        }
+        self.int_enable = false;
+        self.int_delay = false;
+        self.halted = false;
+        self.instr_pc = self.pc;
+        self.call(vector);
+        // Accepted as an internal RST
+        let cycles = self.opcodes()[0xc7].cycles;
+        self.cycles += u64::from(cycles);
+        Some(usize::from(cycles))
+    }
+
+    fn rim(&mut self, _op: u8) {
+        let cycles = self.cycles;
+        let sid = self.io.sid(cycles);
+        let int_enable = self.int_enable;
+        self.a = self.state.i8085.rim(int_enable, sid);
+    }
+
+    fn sim(&mut self, _op: u8) {
+        let a = self.a;
+        if let Some(level) = self.state.i8085.sim(a) {
+            let cycles = self.cycles;
+            self.io.sod(level, cycles);
+        }
+    }
+
+    /// Opcodes missing from the documented instruction set, which the 8085
+    /// decodes as extra instructions.
+    fn undocumented_8085(&mut self, op: u8) {
+        if self.reject(op) {
+            return;
+        }
+        match op {
+            0x08 => self.dsub(),
+            0x10 => self.arhl(),
+            0x18 => self.rdel(),
+            0x28 => {
+                // LDHI
+                let val = self.hl() + usize::from(self.fetch());
+                self.set_long(0x10, (val as u8, (val >> 8) as u8));
+            }
+            0x38 => {
+                // LDSI
+                let val = self.sp + usize::from(self.fetch());
+                self.set_long(0x10, (val as u8, (val >> 8) as u8));
+            }
+            0xcb => {
+                // RSTV
+                if self.condition(op) {
+                    self.call(RSTV_VECTOR);
+                }
+            }
+            0xd9 => {
+                // SHLX
+                let (addr, l, h) = (self.de(), self.l, self.h);
+                self.write_byte(addr, l);
+                self.write_byte(addr + 1, h);
+            }
+            0xed => {
+                // LHLX
+                let addr = self.de();
+                self.l = self.read_byte(addr);
+                self.h = self.read_byte((addr + 1) & 0xFFFF);
+            }
+            _ => {
+                // JNK, JK
+                let addr = self.fetch_word();
+                if self.condition(op) {
+                    self.pc = usize::from(addr);
+                }
+            }
+        }
+    }
+
+    /// HL -= BC, setting all flags.
+    fn dsub(&mut self) {
+        let (l, c) = (self.l, self.c);
+        let low = self.sub_flags(l, c, false);
+        let (h, b, borrow) = (self.h, self.b, self.fl.cy);
+        let high = self.sub_flags(h, b, borrow);
+        self.l = low;
+        self.h = high;
+        self.fl.z = low | high == 0;
+    }
+
+    /// Shifts HL right, keeping the sign bit. CY receives bit 0.
+    fn arhl(&mut self) {
+        let hl = self.hl();
+        self.fl.cy = hl & 1 != 0;
+        let val = (hl >> 1) | (hl & 0x8000);
+        self.set_long(0x20, (val as u8, (val >> 8) as u8));
+    }
+
+    /// Rotates DE left through CY.
+    fn rdel(&mut self) {
+        let de = self.de();
+        let val = (de << 1) | usize::from(self.fl.cy);
+        self.fl.cy = de & 0x8000 != 0;
+        self.set_long(0x10, (val as u8, (val >> 8) as u8));
+    }
+
+    const fn handler_8085(op: u8) -> Instruction<T, M, I> {
+        match op {
+            0x20 => Self::rim,
+            0x30 => Self::sim,
+            0x08 | 0x10 | 0x18 | 0x28 | 0x38 => Self::undocumented_8085,
+            0xcb | 0xd9 | 0xdd | 0xed | 0xfd => Self::undocumented_8085,
+            _ => Self::handler(op),
+        }
+    }
+
+    pub(crate) const HANDLERS_8085: [Instruction<T, M, I>; 256] = {
+        let mut table = [Self::handler(0); 256];
+        let mut i = 0;
+        while i < 256 {
+            table[i] = Self::handler_8085(i as u8);
+            i += 1;
+        }
+        table
+    };
+}
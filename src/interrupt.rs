@@ -0,0 +1,109 @@
+/// An instruction placed on the data bus by an interrupting device during
+/// the interrupt acknowledge cycle.
+///
+/// The CPU executes it without advancing the program counter, so a CALL
+/// pushes the address of the interrupted instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusInstruction {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl BusInstruction {
+    pub fn new(bytes: &[u8]) -> Self {
+        assert!(
+            !bytes.is_empty() && bytes.len() <= 3,
+            "Bus instructions are 1 to 3 bytes long"
+        );
+        let mut instr = BusInstruction {
+            bytes: [0xFF; 3],
+            len: bytes.len(),
+        };
+        instr.bytes[..bytes.len()].copy_from_slice(bytes);
+        instr
+    }
+
+    /// `RST num`, the usual single byte interrupt vector.
+    pub fn rst(num: u8) -> Self {
+        BusInstruction::new(&[0xc7 | ((num & 7) << 3)])
+    }
+
+    /// `CALL addr`, as supplied by vectored interrupt controllers like the
+    /// 8259.
+    pub fn call(addr: u16) -> Self {
+        BusInstruction::new(&[0xcd, addr as u8, (addr >> 8) as u8])
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Returns the byte read by the CPU at position `i` of the instruction.
+    /// Past the end of the instruction, the floating data bus reads as 0xFF.
+    pub fn byte(&self, i: usize) -> u8 {
+        if i < self.len {
+            self.bytes[i]
+        } else {
+            0xFF
+        }
+    }
+}
+
+/// A device driving the CPU's INT line.
+///
+/// The emulator polls `pending` at each instruction boundary while
+/// interrupts are enabled, and calls `acknowledge` to fetch the instruction
+/// to execute when it accepts the interrupt.
+pub trait InterruptSource {
+    /// Whether the INT line is asserted.
+    fn pending(&mut self) -> bool;
+
+    /// Called when the CPU accepts the interrupt.
+    fn acknowledge(&mut self) -> BusInstruction;
+}
+
+/// No device: the INT line is never asserted.
+#[derive(Default)]
+pub struct NoInterrupts;
+
+impl InterruptSource for NoInterrupts {
+    fn pending(&mut self) -> bool {
+        false
+    }
+
+    fn acknowledge(&mut self) -> BusInstruction {
+        BusInstruction::new(&[0xFF])
+    }
+}
+
+/// A latch holding a single `RST n` request until the CPU accepts it, as in
+/// Space Invaders' video hardware.
+#[derive(Default)]
+pub struct RstInterrupts {
+    request: Option<u8>,
+}
+
+impl RstInterrupts {
+    /// Requests `RST num`, replacing any request not yet accepted.
+    pub fn request(&mut self, num: u8) {
+        self.request = Some(num);
+    }
+}
+
+impl InterruptSource for RstInterrupts {
+    fn pending(&mut self) -> bool {
+        self.request.is_some()
+    }
+
+    fn acknowledge(&mut self) -> BusInstruction {
+        BusInstruction::rst(self.request.take().unwrap_or(7))
+    }
+}
+
+#[test]
+fn bus_instruction_test() {
+    assert_eq!(BusInstruction::rst(2).bytes(), &[0xd7]);
+    let call = BusInstruction::call(0x1234);
+    assert_eq!(call.bytes(), &[0xcd, 0x34, 0x12]);
+    assert_eq!(call.byte(3), 0xFF);
+}
@@ -3,73 +3,157 @@ use std::io::prelude::*;
 use std::num::Wrapping;
 use std::ops::{Deref, DerefMut};
 
+pub mod asm;
+pub mod cpm;
 pub mod dis;
+pub mod error;
+pub mod i8085;
+pub mod interrupt;
+pub mod memory;
+pub mod opcodes;
+pub mod scheduler;
 pub mod state;
-
-use dis::disassemble8080_op;
+pub mod symbols;
+pub mod z80;
+
+use dis::{decode, Format, Syntax};
+use error::StepError;
+use interrupt::*;
+use memory::*;
+use opcodes::Opcode;
+use scheduler::Scheduler;
 use state::*;
+use symbols::Symbols;
 
+/// The devices answering IN and OUT instructions.
+///
+/// `cycles` is the value of the CPU's cycle counter at the start of the
+/// instruction, for devices that need to timestamp accesses.
 pub trait InOutHandler {
-    fn read(&mut self, port: u8) -> u8;
-    fn write(&mut self, port: u8, val: u8);
+    fn read(&mut self, port: u8, cycles: u64) -> u8;
+    fn write(&mut self, port: u8, val: u8, cycles: u64);
+
+    /// Level of the 8085's SID serial input, read by RIM.
+    fn sid(&mut self, _cycles: u64) -> bool {
+        false
+    }
+
+    /// Called when SIM sets the 8085's SOD serial output to `level`.
+    fn sod(&mut self, _level: bool, _cycles: u64) {}
 }
 
 #[derive(Default)]
 pub struct DefaultHandler;
 
 impl InOutHandler for DefaultHandler {
-    fn read(&mut self, _port: u8) -> u8 {
+    fn read(&mut self, _port: u8, _cycles: u64) -> u8 {
         0
     }
-    fn write(&mut self, _port: u8, _val: u8) {}
+    fn write(&mut self, _port: u8, _val: u8, _cycles: u64) {}
 }
 
 #[derive(Default)]
-pub struct Emu8080<T: InOutHandler = DefaultHandler> {
-    pub state: State8080,
+pub struct Emu8080<
+    T: InOutHandler = DefaultHandler,
+    M: MemoryBus = FlatMemory,
+    I: InterruptSource = NoInterrupts,
+> {
+    pub state: State8080<M>,
     pub io: T,
+    pub interrupts: I,
+    /// Device events fired at instruction boundaries by the `run_*`
+    /// methods.
+    pub events: Scheduler<Self>,
+    /// Print each instruction and the resulting registers as they are
+    /// executed by the `run_*` methods.
+    pub trace: bool,
+    /// Names and comments shown in traces.
+    pub symbols: Symbols,
+    /// Report undocumented opcodes as `StepError::IllegalOpcode` instead of
+    /// executing them as the aliases the 8080 decodes them to.
+    pub strict: bool,
+    /// Report pushes and pops that carry the stack pointer across the top
+    /// or bottom of the address space as `StepError::StackWraparound`. They
+    /// still wrap around, as on hardware.
+    pub strict_stack: bool,
 }
 
-impl<T: InOutHandler> Deref for Emu8080<T> {
-    type Target = State8080;
+impl<T: InOutHandler, M: MemoryBus, I: InterruptSource> Deref for Emu8080<T, M, I> {
+    type Target = State8080<M>;
 
     fn deref(&self) -> &Self::Target {
         &self.state
     }
 }
 
-impl<T: InOutHandler> DerefMut for Emu8080<T> {
+impl<T: InOutHandler, M: MemoryBus, I: InterruptSource> DerefMut for Emu8080<T, M, I> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.state
     }
 }
 
-type Instruction<T> = fn(&mut Emu8080<T>, u8) -> usize;
+/// The result of successfully executing one instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepOutcome {
+    pub cycles: usize,
+}
+
+/// Why one of the `run_*` methods returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The cycle budget was used up.
+    Budget,
+    /// The breakpoint predicate held before the next instruction.
+    Breakpoint,
+    /// The CPU executed HLT and is waiting for an interrupt. Running again
+    /// idles until one is accepted.
+    Halted,
+    /// An instruction failed. The cycles of one that completed anyway, like
+    /// a push that wrapped the stack around, are counted. Illegal opcodes
+    /// in strict mode take none.
+    Error(StepError),
+}
+
+/// The result of executing a batch of instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunOutcome {
+    /// Cycles taken by the executed instructions. The last one may run past
+    /// the budget.
+    pub cycles: u64,
+    pub stop: StopReason,
+}
+
+type Instruction<T, M, I> = fn(&mut Emu8080<T, M, I>, u8);
 
 impl<T: InOutHandler> Emu8080<T> {
     pub fn new(io_handler: T) -> Self {
+        Emu8080::with_memory(io_handler, FlatMemory::default())
+    }
+}
+
+impl<T: InOutHandler, M: MemoryBus> Emu8080<T, M> {
+    pub fn with_memory(io_handler: T, memory: M) -> Self {
+        Emu8080::with_interrupts(io_handler, memory, NoInterrupts)
+    }
+}
+
+impl<T: InOutHandler, M: MemoryBus, I: InterruptSource> Emu8080<T, M, I> {
+    pub fn with_interrupts(io_handler: T, memory: M, interrupts: I) -> Self {
+        Emu8080::with_model(io_handler, memory, interrupts, Model::I8080)
+    }
+
+    pub fn with_model(io_handler: T, memory: M, interrupts: I, model: Model) -> Self {
+        let mut state = State8080::new(memory);
+        state.model = model;
         Emu8080 {
-            state: State8080 {
-                a: 0,
-                b: 0,
-                c: 0,
-                d: 0,
-                e: 0,
-                h: 0,
-                l: 0,
-                sp: 0,
-                pc: 0,
-                memory: vec![0xFF; 0x10000],
-                fl: Flags {
-                    z: false,
-                    s: false,
-                    p: false,
-                    cy: false,
-                    ac: false,
-                },
-                int_enable: false,
-            },
+            state,
             io: io_handler,
+            interrupts,
+            events: Scheduler::default(),
+            trace: false,
+            symbols: Symbols::new(),
+            strict: false,
+            strict_stack: false,
         }
     }
 
@@ -86,76 +170,191 @@ impl<T: InOutHandler> Emu8080<T> {
         filename: &str,
         offset: usize,
     ) -> std::io::Result<usize> {
-        File::open(filename)?.read(&mut self.memory[offset..])
+        let mut buf = vec![0; 0x10000 - offset];
+        let len = File::open(filename)?.read(&mut buf)?;
+        self.load_at(offset, &buf[..len]);
+        Ok(len)
+    }
+
+    /// Copies `bytes` into memory starting at `offset`, bypassing any
+    /// protection the memory bus may apply to CPU writes.
+    pub fn load_at(&mut self, offset: usize, bytes: &[u8]) {
+        let cycles = self.cycles;
+        for (i, &b) in bytes.iter().enumerate() {
+            self.memory.write((offset + i) as u16, b, cycles);
+        }
+    }
+
+    /// Copies the bytes of an assembled program into memory, like
+    /// `load_at`.
+    pub fn load_program(&mut self, program: &asm::Program) {
+        for (addr, bytes) in program.segments() {
+            self.load_at(usize::from(addr), bytes);
+        }
+    }
+
+    /// Calls `callback` at the first instruction boundary where the cycle
+    /// counter has reached `at`, while running with the `run_*` methods.
+    ///
+    /// Instructions are not interrupted, so the callback fires up to one
+    /// instruction late: at cycle 30 for an event at 25 during a `JMP`
+    /// from cycle 20. It receives the cycle it was scheduled at, and
+    /// returns the cycle to call it again at, if any. Periodic events that
+    /// reschedule from that cycle do not accumulate the delay.
+    pub fn schedule<F>(&mut self, at: u64, callback: F)
+    where
+        F: FnMut(&mut Self, u64) -> Option<u64> + Send + 'static,
+    {
+        self.events.schedule(at, callback);
+    }
+
+    fn fire_events(&mut self) {
+        while let Some((at, mut callback)) = self.events.pop_due(self.state.cycles) {
+            if let Some(next) = callback(self, at) {
+                assert!(next > at, "Event at {} rescheduled at {}", at, next);
+                self.events.reschedule(next, callback);
+            }
+        }
     }
 
+    /// Requests interrupt `interrupt_num`, placing `RST interrupt_num` on
+    /// the data bus.
     pub fn generate_interrupt(&mut self, interrupt_num: u8) {
-        if self.int_enable {
-            // println!("* Generating interrupt {}", interrupt_num);
-            self.int_enable = false;
-            self.push(self.pc as u16);
-            self.pc = usize::from(interrupt_num << 3);
+        self.interrupt(BusInstruction::rst(interrupt_num));
+    }
+
+    /// Requests an interrupt with `instr` on the data bus, as an interrupting
+    /// device would during the acknowledge cycle.
+    ///
+    /// The request is held on the INT line until the CPU accepts it at an
+    /// instruction boundary, replacing any request not yet accepted.
+    pub fn interrupt(&mut self, instr: BusInstruction) {
+        self.int_request = Some(instr);
+    }
+
+    /// Whether the INT line is asserted, either by `interrupt` or by the
+    /// interrupt source.
+    pub fn interrupt_pending(&mut self) -> bool {
+        self.int_request.is_some() || self.interrupts.pending()
+    }
+
+    /// Samples the INT line, which is ignored until the instruction following
+    /// EI has completed.
+    fn sample_interrupt(&mut self) -> Option<BusInstruction> {
+        let delayed = self.int_delay;
+        self.int_delay = false;
+        if !self.int_enable || delayed {
+            None
+        } else if let Some(instr) = self.int_request.take() {
+            Some(instr)
+        } else if self.interrupts.pending() {
+            Some(self.interrupts.acknowledge())
+        } else {
+            None
         }
     }
 
-    fn add(&mut self, val: u8) {
-        self.fl.ac = (self.a & 0xf) + (val & 0xf) > 0xf;
-        let ans = u16::from(self.a) + u16::from(val);
+    fn acknowledge(&mut self, instr: BusInstruction) -> usize {
+        if self.model == Model::Z80 {
+            return self.acknowledge_z80(instr);
+        }
+        self.int_enable = false;
+        self.halted = false;
+        self.instr_pc = self.pc;
+        self.bus = Some((instr, 0));
+        let opcode = self.fetch();
+        let cycles = self.dispatch(opcode);
+        self.bus = None;
+        cycles
+    }
+
+    /// Adds `rhs` and `carry` to `lhs`, setting all flags.
+    fn add_flags(&mut self, lhs: u8, rhs: u8, carry: bool) -> u8 {
+        let carry = u8::from(carry);
+        let ans = u16::from(lhs) + u16::from(rhs) + u16::from(carry);
+        self.fl.ac = (lhs & 0xf) + (rhs & 0xf) + carry > 0xf;
         self.set_flags(ans);
-        self.a = ans as u8;
+        // Overflow when both operands have the same sign and the result
+        // does not
+        let overflow = (lhs ^ ans as u8) & (rhs ^ ans as u8) & 0x80 != 0;
+        self.fl.v = overflow;
+        self.fl.k = self.fl.s ^ overflow;
+        ans as u8
+    }
+
+    /// Subtracts `rhs` and `borrow` from `lhs`, setting all flags.
+    ///
+    /// The 8080 adds the one's complement of `rhs` with an inverted borrow:
+    /// AC is the carry out of bit 3 of that addition, and CY is the inverted
+    /// carry out of bit 7, so that it is set on borrow.
+    fn sub_flags(&mut self, lhs: u8, rhs: u8, borrow: bool) -> u8 {
+        let ans = self.add_flags(lhs, !rhs, !borrow);
+        self.fl.cy = !self.fl.cy;
+        ans
+    }
+
+    fn add(&mut self, val: u8) {
+        self.a = self.add_flags(self.a, val, false);
     }
 
     fn adc(&mut self, val: u8) {
-        let cy = Wrapping(if self.fl.cy { 1 } else { 0 });
-        let Wrapping(rhs) = Wrapping(val) + cy;
-        self.add(rhs);
+        self.a = self.add_flags(self.a, val, self.fl.cy);
     }
 
     fn sub(&mut self, val: u8) {
-        let Wrapping(rhs) = Wrapping(!val) + Wrapping(1);
-        self.add(rhs);
+        self.a = self.sub_flags(self.a, val, false);
     }
 
     fn sbb(&mut self, val: u8) {
-        let carry = Wrapping(if self.fl.cy { 1u8 } else { 0u8 });
-        let Wrapping(rhs) = Wrapping(val) + carry;
-        self.sub(rhs);
+        self.a = self.sub_flags(self.a, val, self.fl.cy);
     }
 
     fn and(&mut self, val: u8) {
+        // AC is the OR of bit 3 of both operands, or always set on the 8085
+        let ac = self.model == Model::I8085 || (self.a | val) & 0x08 != 0;
         self.a &= val;
         let temp = self.a;
         self.set_flags(temp.into());
+        self.fl.ac = ac;
     }
 
     fn xor(&mut self, val: u8) {
         self.a ^= val;
         let temp = self.a;
         self.set_flags(temp.into());
+        self.fl.ac = false;
     }
 
     fn or(&mut self, val: u8) {
         self.a |= val;
         let temp = self.a;
         self.set_flags(temp.into());
+        self.fl.ac = false;
     }
 
     fn cmp(&mut self, val: u8) {
-        let ans = Wrapping(u16::from(self.a)) - Wrapping(u16::from(val));
-        self.set_flags(ans.0);
+        self.sub_flags(self.a, val, false);
     }
 
     fn pop(&mut self) -> u16 {
-        let r = (u16::from(self.memory[self.sp + 1]) << 8) | u16::from(self.memory[self.sp]);
-        self.sp += 2;
+        let sp = self.sp;
+        let r = self.word_at(sp);
+        if self.strict_stack && sp > 0xFFFD {
+            self.fault(StepError::StackWraparound { sp });
+        }
+        self.sp = (sp + 2) & 0xFFFF;
         r
     }
 
     fn push(&mut self, val: u16) {
-        self.sp -= 2;
         let sp = self.sp;
-        self.memory[sp] = (val & 0xff) as u8;
-        self.memory[sp + 1] = (val >> 8) as u8;
+        if self.strict_stack && sp < 2 {
+            self.fault(StepError::StackWraparound { sp });
+        }
+        self.sp = sp.wrapping_sub(2) & 0xFFFF;
+        let sp = self.sp;
+        self.write_byte(sp, (val & 0xff) as u8);
+        self.write_byte(sp + 1, (val >> 8) as u8);
     }
 
     pub fn ret(&mut self) {
@@ -164,495 +363,627 @@ impl<T: InOutHandler> Emu8080<T> {
 
     pub fn call(&mut self, addr: u16) {
         let pc = self.pc as u16;
-        self.push(pc + 2);
+        self.push(pc);
         self.pc = usize::from(addr);
     }
 
-    fn call_instr(&mut self, op: u8) -> usize {
+    fn nop(&mut self, _op: u8) {}
+
+    fn call_instr(&mut self, _op: u8) {
+        let addr = self.fetch_word();
+        self.call(addr);
+    }
+
+    fn call_cond(&mut self, op: u8) {
+        let addr = self.fetch_word();
         if self.get_flag(op) {
-            self.call(self.word());
-            17
-        } else {
-            self.pc += 2;
-            11
+            self.call(addr);
         }
     }
 
-    fn ret_instr(&mut self, op: u8) -> usize {
+    fn ret_instr(&mut self, _op: u8) {
+        self.ret();
+    }
+
+    fn ret_cond(&mut self, op: u8) {
         if self.get_flag(op) {
             self.ret();
-            if op & 0b1 == 1 {
-                10
-            } else {
-                11
-            }
-        } else {
-            5
         }
     }
 
-    fn jmp_instr(&mut self, op: u8) -> usize {
+    fn jmp_instr(&mut self, _op: u8) {
+        self.pc = usize::from(self.fetch_word());
+    }
+
+    fn jmp_cond(&mut self, op: u8) {
+        let addr = self.fetch_word();
         if self.get_flag(op) {
-            self.pc = self.word() as usize;
-        } else {
-            self.pc += 2;
-        }
-        10
-    }
-
-    fn lxi(&mut self, op: u8) -> usize {
-        self.set_long(op, (self.byte1(), self.byte2()));
-        self.pc += 2;
-        10
-    }
-
-    fn ldax(&mut self, op: u8) -> usize {
-        if op == 0x2A {
-            // LHLD
-            let addr = self.word() as usize;
-            self.l = self.memory[addr];
-            self.h = self.memory[addr + 1];
-            self.pc += 2;
-            16
-        } else if op == 0x3A {
-            let addr = self.word() as usize;
-            self.a = self.memory[addr];
-            self.pc += 2;
-            13
-        } else {
-            let addr = self.get_long(op);
-            self.a = self.memory[addr];
-            7
-        }
-    }
-
-    fn stax(&mut self, op: u8) -> usize {
-        if op == 0x22 {
-            // SHLD
-            let addr = self.word();
-            self.memory[usize::from(addr)] = self.l;
-            self.memory[usize::from(addr + 1)] = self.h;
-            self.pc += 2;
-            16
-        } else if op == 0x32 {
-            let addr = self.word() as usize;
-            self.memory[addr] = self.a;
-            self.pc += 2;
-            13
-        } else {
-            let addr = self.get_long(op);
-            self.memory[addr] = self.a;
-            7
+            self.pc = usize::from(addr);
+        }
+    }
+
+    /// In strict mode, reports the undocumented `op` as illegal and rewinds
+    /// to it. Returns whether it must not be executed.
+    fn reject(&mut self, op: u8) -> bool {
+        if self.strict {
+            let pc = self.instr_pc;
+            self.pc = pc;
+            self.fault(StepError::IllegalOpcode { opcode: op, pc });
+        }
+        self.strict
+    }
+
+    /// Opcodes missing from the documented instruction set, which the 8080
+    /// decodes as NOP, JMP, RET or CALL.
+    fn undocumented(&mut self, op: u8) {
+        if self.reject(op) {
+            return;
+        }
+        match op {
+            0xcb => self.jmp_instr(op),
+            0xd9 => self.ret_instr(op),
+            0xdd | 0xed | 0xfd => self.call_instr(op),
+            _ => self.nop(op),
         }
     }
 
-    fn inx(&mut self, op: u8) -> usize {
+    fn lxi(&mut self, op: u8) {
+        let low = self.fetch();
+        let high = self.fetch();
+        self.set_long(op, (low, high));
+    }
+
+    fn lhld(&mut self, _op: u8) {
+        let addr = self.fetch_word() as usize;
+        self.l = self.read_byte(addr);
+        self.h = self.read_byte(addr + 1);
+    }
+
+    fn lda(&mut self, _op: u8) {
+        let addr = self.fetch_word() as usize;
+        self.a = self.read_byte(addr);
+    }
+
+    fn ldax(&mut self, op: u8) {
+        let addr = self.get_long(op);
+        self.a = self.read_byte(addr);
+    }
+
+    fn shld(&mut self, _op: u8) {
+        let addr = self.fetch_word() as usize;
+        let (l, h) = (self.l, self.h);
+        self.write_byte(addr, l);
+        self.write_byte(addr + 1, h);
+    }
+
+    fn sta(&mut self, _op: u8) {
+        let addr = self.fetch_word() as usize;
+        let a = self.a;
+        self.write_byte(addr, a);
+    }
+
+    fn stax(&mut self, op: u8) {
+        let addr = self.get_long(op);
+        let a = self.a;
+        self.write_byte(addr, a);
+    }
+
+    fn inx(&mut self, op: u8) {
         let val = self.get_long(op) + 1;
+        self.fl.k = val > 0xFFFF;
         self.set_long(op, (val as u8, (val >> 8) as u8));
-        5
     }
 
-    fn inr(&mut self, op: u8) -> usize {
+    fn inr(&mut self, op: u8) {
         let reg = (op >> 3) & 7;
         let lhs = self.get_register(reg);
         self.fl.ac = (lhs & 0xf) + 1 > 0xf;
         let Wrapping(val) = Wrapping(lhs) + Wrapping(1);
         self.set_register(reg, val);
         self.set_r(val);
-        5
     }
 
-    fn dcr(&mut self, op: u8) -> usize {
+    fn dcr(&mut self, op: u8) {
         let reg = (op >> 3) & 7;
         let lhs = self.get_register(reg);
-        self.fl.ac = (lhs & 0xf) + 1 > 0xf;
+        // Decrementing adds 0xFF, which carries out of bit 3 unless the low
+        // nibble is 0
+        self.fl.ac = lhs & 0xf != 0;
         let Wrapping(val) = Wrapping(lhs) - Wrapping(1);
         self.set_register(reg, val);
         self.set_r(val);
-        5
     }
 
-    fn mvi(&mut self, op: u8) -> usize {
+    fn mvi(&mut self, op: u8) {
         let reg = (op >> 3) & 7;
-        self.set_register(reg, self.byte1());
-        self.pc += 1;
-        if reg == 6 {
-            10
-        } else {
-            7
-        }
+        let val = self.fetch();
+        self.set_register(reg, val);
     }
 
-    fn dad(&mut self, op: u8) -> usize {
+    fn dad(&mut self, op: u8) {
         let Wrapping(val) = Wrapping(self.hl()) + Wrapping(self.get_long(op));
         self.set_long(0x20, (val as u8, (val >> 8) as u8));
         self.fl.cy = val > 0xFFFF;
-        10
     }
 
-    fn dcx(&mut self, op: u8) -> usize {
+    fn dcx(&mut self, op: u8) {
+        self.fl.k = self.get_long(op) == 0;
         let Wrapping(val) = Wrapping(self.get_long(op)) - Wrapping(1);
         self.set_long(op, (val as u8, (val >> 8) as u8));
-        5
     }
 
-    fn mov(&mut self, op: u8) -> usize {
-        if op == 0x76 {
-            // HLT
-            return 0;
-        }
+    fn mov(&mut self, op: u8) {
         let src = op & 0b111;
         let dst = (op >> 3) & 0b111;
         let val = self.get_register(src);
         self.set_register(dst, val);
-        if src == 6 || dst == 6 {
-            7
-        } else {
-            5
-        }
     }
 
-    fn add_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = u16::from(self.get_register(reg)) + u16::from(self.a);
-        self.set_flags(val);
-        self.a = val as u8;
-        if reg == 6 {
-            7
-        } else {
-            4
-        }
+    fn hlt(&mut self, _op: u8) {
+        self.halted = true;
+        self.idle();
     }
 
-    fn adc_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
+    /// ADD, ADC, SUB, SBB, ANA, XRA, ORA and CMP with a register operand.
+    fn alu_instr(&mut self, op: u8) {
         let val = self.get_register(op & 0b111);
-        let mut cy = if self.fl.cy { 1u8 } else { 0u8 };
-        self.add(val);
-        if self.fl.cy {
-            cy += 1
+        self.alu(op, val);
+    }
+
+    /// The same operations with an immediate operand.
+    fn immediate(&mut self, op: u8) {
+        let val = self.fetch();
+        self.alu(op, val);
+    }
+
+    fn alu(&mut self, op: u8, val: u8) {
+        match (op >> 3) & 7 {
+            0 => self.add(val),
+            1 => self.adc(val),
+            2 => self.sub(val),
+            3 => self.sbb(val),
+            4 => self.and(val),
+            5 => self.xor(val),
+            6 => self.or(val),
+            7 => self.cmp(val),
+            _ => unreachable!(),
         };
-        self.add(cy);
-        if reg == 6 {
-            7
+    }
+
+    fn pop_instr(&mut self, op: u8) {
+        let val = self.pop();
+        if op == 0xf1 {
+            // POP PSW
+            self.a = (val >> 8) as u8;
+            self.fl = match self.model {
+                Model::I8080 => Flags::from_psw(val as u8),
+                Model::I8085 => Flags::from_psw_8085(val as u8),
+                Model::Z80 => Flags::from_psw_z80(val as u8),
+            };
         } else {
-            4
+            self.set_long(op, (val as u8, (val >> 8) as u8));
         }
     }
 
-    fn sub_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        self.sub(val);
-        if reg == 6 {
-            7
+    fn push_instr(&mut self, op: u8) {
+        let val = if op == 0xf5 {
+            // PUSH PSW
+            let psw = match self.model {
+                Model::I8080 => self.fl.to_psw(),
+                Model::I8085 => self.fl.to_psw_8085(),
+                Model::Z80 => self.fl.to_psw_z80(),
+            };
+            (u16::from(self.a) << 8) | u16::from(psw)
         } else {
-            4
+            self.get_long(op) as u16
+        };
+        self.push(val);
+    }
+
+    fn rst(&mut self, op: u8) {
+        let pc = self.pc as u16;
+        self.push(pc);
+        self.pc = usize::from(op & 0b111_000);
+    }
+
+    fn rlc(&mut self, _op: u8) {
+        let bit = self.a >> 7;
+        self.a = (self.a << 1) | bit;
+        self.fl.cy = bit == 1;
+    }
+
+    fn rrc(&mut self, _op: u8) {
+        let bit = self.a << 7;
+        self.a = (self.a >> 1) | bit;
+        self.fl.cy = bit > 0;
+    }
+
+    fn ral(&mut self, _op: u8) {
+        let prev_carry = if self.fl.cy { 1 } else { 0 };
+        self.fl.cy = (self.a >> 7) == 1;
+        self.a = (self.a << 1) | prev_carry;
+    }
+
+    fn rar(&mut self, _op: u8) {
+        let prev_carry = if self.fl.cy { 1 } else { 0 };
+        self.fl.cy = (self.a & 1) == 1;
+        self.a = (self.a >> 1) | (prev_carry << 7);
+    }
+
+    fn daa(&mut self, _op: u8) {
+        let mut correction = 0;
+        let mut cy = self.fl.cy;
+        let low = self.a & 0xf;
+        let high = self.a >> 4;
+        if self.fl.ac || low > 9 {
+            correction |= 0x06;
+        }
+        if cy || high > 9 || (high == 9 && low > 9) {
+            correction |= 0x60;
+            cy = true;
         }
+        self.add(correction);
+        self.fl.cy = cy;
     }
 
-    fn sbb_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        let carry = Wrapping(if self.fl.cy { 1u8 } else { 0u8 });
-        let Wrapping(rhs) = Wrapping(val) + carry;
-        self.sub(rhs);
-        if reg == 6 {
-            7
-        } else {
-            4
+    fn cma(&mut self, _op: u8) {
+        self.a = !self.a;
+    }
+
+    fn stc(&mut self, _op: u8) {
+        self.fl.cy = true;
+    }
+
+    fn cmc(&mut self, _op: u8) {
+        self.fl.cy = !self.fl.cy;
+    }
+
+    fn out(&mut self, _op: u8) {
+        let port = self.fetch();
+        self.io.write(port, self.state.a, self.state.cycles);
+    }
+
+    fn in_instr(&mut self, _op: u8) {
+        let port = self.fetch();
+        self.a = self.io.read(port, self.state.cycles);
+    }
+
+    fn xthl(&mut self, _op: u8) {
+        // Exchanged in place, SP is left alone
+        let sp = self.sp;
+        let val = self.word_at(sp);
+        let (h, l) = (self.h, self.l);
+        self.write_byte(sp, l);
+        self.write_byte(sp + 1, h);
+        self.h = (val >> 8) as u8;
+        self.l = val as u8;
+    }
+
+    fn xchg(&mut self, _op: u8) {
+        std::mem::swap(&mut self.state.d, &mut self.state.h);
+        std::mem::swap(&mut self.state.e, &mut self.state.l);
+    }
+
+    fn pchl(&mut self, _op: u8) {
+        self.pc = self.hl();
+    }
+
+    fn sphl(&mut self, _op: u8) {
+        self.sp = self.hl();
+    }
+
+    fn di(&mut self, _op: u8) {
+        self.int_enable = false;
+    }
+
+    fn ei(&mut self, _op: u8) {
+        self.int_enable = true;
+        self.int_delay = true;
+    }
+
+    const fn handler(op: u8) -> Instruction<T, M, I> {
+        match op {
+            0x01 | 0x11 | 0x21 | 0x31 => Self::lxi,
+            0x02 | 0x12 => Self::stax,
+            0x22 => Self::shld,
+            0x32 => Self::sta,
+            0x0a | 0x1a => Self::ldax,
+            0x2a => Self::lhld,
+            0x3a => Self::lda,
+            0x07 => Self::rlc,
+            0x0f => Self::rrc,
+            0x17 => Self::ral,
+            0x1f => Self::rar,
+            0x27 => Self::daa,
+            0x2f => Self::cma,
+            0x37 => Self::stc,
+            0x3f => Self::cmc,
+            _ if op & 0xc7 == 0x03 => {
+                if op & 0x08 == 0 {
+                    Self::inx
+                } else {
+                    Self::dcx
+                }
+            }
+            _ if op & 0xc7 == 0x04 => Self::inr,
+            _ if op & 0xc7 == 0x05 => Self::dcr,
+            _ if op & 0xc7 == 0x06 => Self::mvi,
+            _ if op & 0xcf == 0x09 => Self::dad,
+            0x00 => Self::nop,
+            _ if op & 0xc7 == 0x00 => Self::undocumented,
+            0x76 => Self::hlt,
+            0x40..=0x7f => Self::mov,
+            0x80..=0xbf => Self::alu_instr,
+            0xd3 => Self::out,
+            0xdb => Self::in_instr,
+            0xe3 => Self::xthl,
+            0xe9 => Self::pchl,
+            0xeb => Self::xchg,
+            0xf3 => Self::di,
+            0xf9 => Self::sphl,
+            0xfb => Self::ei,
+            0xcb | 0xd9 | 0xdd | 0xed | 0xfd => Self::undocumented,
+            _ if op & 0xc7 == 0xc0 => Self::ret_cond,
+            0xc9 => Self::ret_instr,
+            _ if op & 0xcf == 0xc1 => Self::pop_instr,
+            _ if op & 0xc7 == 0xc2 => Self::jmp_cond,
+            0xc3 => Self::jmp_instr,
+            _ if op & 0xc7 == 0xc4 => Self::call_cond,
+            0xcd => Self::call_instr,
+            _ if op & 0xcf == 0xc5 => Self::push_instr,
+            _ if op & 0xc7 == 0xc6 => Self::immediate,
+            _ => Self::rst,
         }
     }
 
-    fn and_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        self.and(val);
-        if reg == 6 {
-            7
-        } else {
-            4
+    const HANDLERS: [Instruction<T, M, I>; 256] = {
+        let mut table = [Self::nop as Instruction<T, M, I>; 256];
+        let mut i = 0;
+        while i < 256 {
+            table[i] = Self::handler(i as u8);
+            i += 1;
+        }
+        table
+    };
+
+    /// Executes one instruction, reporting anything that prevented it from
+    /// completing normally.
+    ///
+    /// An instruction that completes despite the error, like a push that
+    /// wraps the stack around, still adds its cycles to `cycles`.
+    pub fn try_step(&mut self) -> Result<StepOutcome, StepError> {
+        self.check_pc()?;
+        let cycles = self.execute();
+        match self.take_fault() {
+            Some(err) => Err(err),
+            None => Ok(StepOutcome { cycles }),
         }
     }
 
-    fn xor_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        self.xor(val);
-        if reg == 6 {
-            7
-        } else {
-            4
+    /// Executes one instruction and returns the number of cycles it took.
+    ///
+    /// Panics on ROM write errors, illegal opcodes in strict mode and when
+    /// the program counter leaves mapped memory. Stack wraparound behaves
+    /// as on hardware, and a halted CPU idles for 7 cycles per step.
+    pub fn step(&mut self) -> usize {
+        if let Err(err) = self.check_pc() {
+            panic!("{}", err);
+        }
+        let cycles = self.execute();
+        match self.take_fault() {
+            Some(err @ StepError::RomWrite(_)) => panic!("{}", err),
+            Some(err @ StepError::IllegalOpcode { .. }) => panic!("{}", err),
+            _ => cycles,
         }
     }
 
-    fn or_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        self.or(val);
-        if reg == 6 {
-            7
+    fn check_pc(&self) -> Result<(), StepError> {
+        let pc = self.pc;
+        if pc > 0xFFFF || !self.memory.is_mapped(pc as u16) {
+            Err(StepError::PcOutOfMemory { pc })
         } else {
-            4
+            Ok(())
         }
     }
 
-    fn cmp_instr(&mut self, op: u8) -> usize {
-        let reg = op & 0b111;
-        let val = self.get_register(reg);
-        self.cmp(val);
-        if reg == 6 {
-            7
-        } else {
-            4
+    /// Whether the CPU is stopped on a HLT instruction, waiting for an
+    /// interrupt.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn idle(&mut self) {
+        // TRAP and NMI can still wake up an 8085 or a Z80
+        if !self.int_enable && self.model == Model::I8080 {
+            // Nothing can wake the CPU up
+            let pc = self.pc.wrapping_sub(1) & 0xFFFF;
+            self.fault(StepError::Halted { pc });
         }
     }
 
-    fn pop_instr(&mut self, op: u8) -> usize {
-        let val = self.pop();
-        if op == 0xf1 {
-            // POP PSW
-            self.a = (val >> 8) as u8;
-            self.fl.s = val & 0x80 > 0;
-            self.fl.z = val & 0x40 > 0;
-            self.fl.ac = val & 0x10 > 0;
-            self.fl.p = val & 0x04 > 0;
-            self.fl.cy = val & 0x01 > 0;
-        } else {
-            self.set_long(op, (val as u8, (val >> 8) as u8));
+    /// Accepts an interrupt at an instruction boundary, returning the cycles
+    /// taken.
+    fn service_interrupt(&mut self) -> Option<usize> {
+        let cycles = match self.model {
+            Model::I8080 => None,
+            Model::I8085 => self.sample_8085(),
+            Model::Z80 => self.sample_nmi(),
+        };
+        if cycles.is_some() {
+            return cycles;
         }
-        10
+        let instr = self.sample_interrupt()?;
+        Some(self.acknowledge(instr))
     }
 
-    fn push_instr(&mut self, op: u8) -> usize {
-        let mut val;
-        if op == 0xf5 {
-            // PUSH PSW
-            val = u16::from(self.a) << 8;
-            if self.fl.s {
-                val |= 0x80
-            };
-            if self.fl.z {
-                val |= 0x40
-            };
-            if self.fl.ac {
-                val |= 0x10
-            };
-            if self.fl.p {
-                val |= 0x04
-            };
-            if self.fl.cy {
-                val |= 0x01
-            };
-        } else {
-            val = self.get_long(op) as u16;
+    fn execute(&mut self) -> usize {
+        if let Some(cycles) = self.service_interrupt() {
+            return cycles;
         }
-        self.push(val);
-        11
+        if self.halted {
+            self.idle();
+            let cycles = self.opcodes()[0x76].cycles;
+            self.cycles += u64::from(cycles);
+            return usize::from(cycles);
+        }
+        self.instr_pc = self.pc;
+        let opcode = self.fetch();
+        self.dispatch(opcode)
     }
 
-    fn rst(&mut self, op: u8) -> usize {
-        let num = (op >> 3) & 0b111;
-        self.generate_interrupt(num);
-        11
+    fn opcodes(&self) -> &'static [Opcode; 256] {
+        opcodes::opcodes(self.model)
     }
 
-    fn assignment(&mut self, op: u8) -> usize {
-        Self::ASSIGNMENTS[(op & 0xf) as usize](self, op)
+    /// Whether the condition of the conditional `opcode` holds.
+    fn condition(&self, opcode: u8) -> bool {
+        match opcode {
+            // Undocumented 8085 RSTV, JNK and JK, which are unconditional on
+            // the 8080
+            0xcb => self.fl.v,
+            0xdd => !self.fl.k,
+            0xfd => self.fl.k,
+            _ => self.get_flag(opcode),
+        }
     }
 
-    fn assignment_extra(&mut self, op: u8) -> usize {
-        if op & (1 << 5) != 0 {
-            self.flagop(op)
+    fn dispatch(&mut self, opcode: u8) -> usize {
+        if self.model == Model::Z80 {
+            return self.dispatch_z80(opcode);
+        }
+        let info = &self.opcodes()[usize::from(opcode)];
+        // Conditions are evaluated before the instruction changes any flag
+        let cycles = if info.cycles_taken != info.cycles && self.condition(opcode) {
+            info.cycles_taken
         } else {
-            self.rot(op)
+            info.cycles
+        };
+        let handler = match self.model {
+            Model::I8085 => Self::HANDLERS_8085[usize::from(opcode)],
+            _ => Self::HANDLERS[usize::from(opcode)],
+        };
+        handler(self, opcode);
+        if self.is_rejected() {
+            return 0;
         }
+        self.cycles += u64::from(cycles);
+        usize::from(cycles)
     }
 
-    fn branch(&mut self, op: u8) -> usize {
-        Self::BRANCHING[(op & 0xf) as usize](self, op)
+    /// Runs until at least `budget` cycles have elapsed.
+    pub fn run_cycles(&mut self, budget: u64) -> RunOutcome {
+        self.run_until(budget, |_| false)
     }
 
-    fn rot(&mut self, op: u8) -> usize {
-        match op >> 3 {
-            0 => {
-                // RLC
-                let bit = self.a >> 7;
-                self.a = (self.a << 1) | bit;
-                self.fl.cy = bit == 1;
+    /// Runs until at least `budget` cycles have elapsed or `breakpoint`
+    /// holds, firing scheduled events as they come due.
+    ///
+    /// `breakpoint` is called before each instruction. It does not stop
+    /// the first one, so a run can resume from where the last one stopped.
+    pub fn run_until<F>(&mut self, budget: u64, mut breakpoint: F) -> RunOutcome
+    where
+        F: FnMut(&State8080<M>) -> bool,
+    {
+        let mut cycles = 0;
+        let mut first = true;
+        let stop = loop {
+            self.fire_events();
+            if cycles >= budget {
+                break StopReason::Budget;
             }
-            1 => {
-                // RRC
-                let bit = self.a << 7;
-                self.a = (self.a >> 1) | bit;
-                self.fl.cy = bit > 0;
+            if breakpoint(&self.state) && !first {
+                break StopReason::Breakpoint;
             }
-            2 => {
-                // RAL
-                let prev_carry = if self.fl.cy { 1 } else { 0 };
-                self.fl.cy = (self.a >> 7) == 1;
-                self.a = (self.a << 1) | prev_carry;
+            first = false;
+            let halted = self.halted;
+            let before = self.cycles;
+            let outcome = if self.trace {
+                self.try_step_dis()
+            } else {
+                self.try_step()
+            };
+            match outcome {
+                Ok(outcome) => cycles += outcome.cycles as u64,
+                Err(err) => {
+                    cycles += self.cycles - before;
+                    break StopReason::Error(err);
+                }
             }
-            3 => {
-                // RAR
-                let prev_carry = if self.fl.cy { 1 } else { 0 };
-                self.fl.cy = (self.a & 1) == 1;
-                self.a = (self.a >> 1) | (prev_carry << 7);
+            if self.halted && !halted {
+                break StopReason::Halted;
             }
-            _ => panic!("Invalid rotation operation"),
         };
-        4
+        RunOutcome { cycles, stop }
     }
 
-    fn flagop(&mut self, op: u8) -> usize {
-        match (op >> 3) & 3 {
-            0 => {
-                if self.a & 0xf > 9 || self.fl.ac {
-                    self.add(0x06);
-                }
-                if ((self.a >> 4) & 0xf) > 9 || self.fl.cy {
-                    self.add(0x60);
+    /// Runs a video frame of `frame_cycles`, split into equal parts each
+    /// ending with `RST n` for the next `n` of `interrupts`, as requested
+    /// by video hardware at fixed scanlines.
+    ///
+    /// Halting does not end the frame. Only errors stop it early.
+    pub fn run_frame(&mut self, frame_cycles: u64, interrupts: &[u8]) -> RunOutcome {
+        let mut cycles = 0;
+        let parts = interrupts.len().max(1) as u64;
+        for part in 0..parts {
+            let end = frame_cycles * (part + 1) / parts;
+            while cycles < end {
+                let outcome = self.run_cycles(end - cycles);
+                cycles += outcome.cycles;
+                if let StopReason::Error(_) = outcome.stop {
+                    return RunOutcome { cycles, ..outcome };
                 }
-            }                        // DAA
-            1 => {
-                self.a = !self.a;
-            } // CMA
-            2 => self.fl.cy = true,        // STC
-            3 => self.fl.cy = !self.fl.cy, // CMC
-            _ => unreachable!(),
-        };
-        4
-    }
-
-    fn immediate(&mut self, op: u8) -> usize {
-        let val = self.byte1();
-        match (op >> 3) & 7 {
-            0 => self.add(val),
-            1 => self.adc(val),
-            2 => self.sub(val),
-            3 => self.sbb(val),
-            4 => self.and(val),
-            5 => self.xor(val),
-            6 => self.or(val),
-            7 => self.cmp(val),
-            _ => unreachable!(),
-        };
-        self.pc += 1;
-        7
-    }
-    const ASSIGNMENTS: [Instruction<T>; 16] = [
-        // NOP
-        |_, _| 4,               // 0x0
-        Self::lxi,              // 0x1
-        Self::stax,             // 0x2
-        Self::inx,              // 0x3
-        Self::inr,              // 0x4
-        Self::dcr,              // 0x5
-        Self::mvi,              // 0x6
-        Self::assignment_extra, // 0x7
-        // NOP
-        |_, _| 4,               // 0x8
-        Self::dad,              // 0x9
-        Self::ldax,             // 0xa
-        Self::dcx,              // 0xb
-        Self::inr,              // 0xc
-        Self::dcr,              // 0xd
-        Self::mvi,              // 0xe
-        Self::assignment_extra, // 0xf
-    ];
-    const BRANCHING: [Instruction<T>; 16] = [
-        Self::ret_instr, // 0x0
-        Self::pop_instr, // 0x1
-        Self::jmp_instr, // 0x2
-        |emu, op| match (op >> 4) & 3 {
-            0 => emu.jmp_instr(op),
-            1 => {
-                emu.io.write(emu.byte1(), emu.a);
-                emu.pc += 1;
-                10
-            }
-            2 => {
-                let val = emu.pop();
-                emu.push(emu.hl() as u16);
-                emu.h = (val >> 8) as u8;
-                emu.l = val as u8;
-                18
-            }
-            3 => {
-                emu.int_enable = false;
-                4
-            }
-            _ => unreachable!(),
-        }, // 0x3
-        Self::call_instr, // 0x4
-        Self::push_instr, // 0x5
-        Self::immediate, // 0x6
-        Self::rst,       // 0x7
-        Self::ret_instr, // 0x8
-        |emu, op| match (op >> 4) & 3 {
-            0 => emu.ret_instr(op),
-            1 => 10,
-            2 => {
-                emu.pc = (usize::from(emu.h) << 8) | usize::from(emu.l);
-                5
-            }
-            3 => {
-                emu.sp = emu.hl();
-                5
             }
-            _ => unreachable!(),
-        }, // 0x9
-        Self::jmp_instr, // 0xa
-        |emu, op| match (op >> 4) & 3 {
-            0 => 10,
-            1 => {
-                emu.a = emu.io.read(emu.byte1());
-                emu.pc += 1;
-                10
-            }
-            2 => {
-                std::mem::swap(&mut emu.state.d, &mut emu.state.h);
-                std::mem::swap(&mut emu.state.e, &mut emu.state.l);
-                5
+            if let Some(&vector) = interrupts.get(part as usize) {
+                self.generate_interrupt(vector);
             }
-            3 => {
-                emu.int_enable = true;
-                4
-            }
-            _ => unreachable!(),
-        }, // 0xb
-        Self::call_instr, // 0xc
-        Self::call_instr, // 0xd
-        Self::immediate, // 0xe
-        Self::rst,       // 0xf
-    ];
-
-    pub fn step(&mut self) -> usize {
-        assert!(self.pc < self.memory.len());
-        let opcode = self.memory[self.pc];
-
-        self.pc += 1;
-        let f = match opcode {
-            0x00..=0x3f => Self::assignment,
-            0x40..=0x7f => Self::mov,
-            0x80..=0x87 => Self::add_instr,
-            0x88..=0x8f => Self::adc_instr,
-            0x90..=0x97 => Self::sub_instr,
-            0x98..=0x9f => Self::sbb_instr,
-            0xa0..=0xa7 => Self::and_instr,
-            0xa8..=0xaf => Self::xor_instr,
-            0xb0..=0xb7 => Self::or_instr,
-            0xb8..=0xbf => Self::cmp_instr,
-            0xc0..=0xff => Self::branch,
-        };
-        f(self, opcode)
+        }
+        RunOutcome {
+            cycles,
+            stop: StopReason::Budget,
+        }
     }
 
     pub fn step_dis(&mut self) -> usize {
-        disassemble8080_op(&self.memory, self.pc);
-
+        self.trace_next();
         let res = self.step();
+        self.trace_registers();
+        res
+    }
+
+    /// Same as `try_step`, but prints the instruction and the resulting
+    /// registers like `step_dis`.
+    pub fn try_step_dis(&mut self) -> Result<StepOutcome, StepError> {
+        self.trace_next();
+        let res = self.try_step();
+        self.trace_registers();
+        res
+    }
+
+    fn trace_next(&self) {
+        let mut code = [0; 4];
+        for (i, byte) in code.iter_mut().enumerate() {
+            *byte = self.memory.peek(self.pc.wrapping_add(i) as u16);
+        }
+        let instr = decode(&code, self.pc as u16, self.model);
+        if let Some(name) = self.symbols.name(instr.addr) {
+            println!("{}:", name);
+        }
+        let format = Format {
+            syntax: match self.model {
+                Model::Z80 => Syntax::Zilog,
+                _ => Syntax::Intel,
+            },
+            ..Format::default()
+        };
+        println!(
+            "{:04X} {:02X} {}",
+            self.pc,
+            instr.opcode,
+            instr.display(format, &self.symbols)
+        );
+    }
 
+    fn trace_registers(&self) {
         println!(
             "Registers: A: {:02X} BC: {:02X}{:02X} DE: {:02X}{:02X} HL: {:02X}{:02X}, SP: {:02X}",
             self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.sp,
@@ -661,8 +992,6 @@ impl<T: InOutHandler> Emu8080<T> {
             "Flags: s: {} z: {} p: {} cy: {}",
             self.fl.s, self.fl.z, self.fl.p, self.fl.cy
         );
-
-        res
     }
 }
 
@@ -705,7 +1034,7 @@ mod tests {
         emu.a = 30;
         emu.sub(15);
         assert_eq!(emu.a, 15);
-        assert!(emu.fl.cy);
+        assert!(!emu.fl.cy);
         assert!(!emu.fl.z);
         assert!(emu.fl.p);
         assert!(!emu.fl.s);
@@ -717,7 +1046,7 @@ mod tests {
         emu.a = 9;
         emu.sub(20);
         assert_eq!(emu.a, 245);
-        assert!(!emu.fl.cy);
+        assert!(emu.fl.cy);
         assert!(!emu.fl.z);
         assert!(emu.fl.p);
         assert!(emu.fl.s);
@@ -729,7 +1058,7 @@ mod tests {
         emu.a = 50;
         emu.sub(50);
         assert_eq!(emu.a, 0);
-        assert!(emu.fl.cy);
+        assert!(!emu.fl.cy);
         assert!(emu.fl.z);
         assert!(emu.fl.p);
         assert!(!emu.fl.s);
@@ -760,4 +1089,1163 @@ mod tests {
         assert!(emu.fl.s);
         assert!(emu.fl.ac);
     }
+
+    #[test]
+    fn try_step_halt() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x00, 0x76]); // NOP; HLT
+        assert_eq!(emu.try_step(), Ok(StepOutcome { cycles: 4 }));
+        assert_eq!(emu.try_step(), Err(StepError::Halted { pc: 1 }));
+        assert!(emu.is_halted());
+        assert_eq!(emu.try_step(), Err(StepError::Halted { pc: 1 }));
+    }
+
+    #[test]
+    fn rst_with_interrupts_disabled() {
+        let mut emu = setup();
+        emu.load_at(0x100, &[0xd7]); // RST 2
+        emu.pc = 0x100;
+        emu.sp = 0x2000;
+        assert_eq!(emu.step(), 11);
+        assert_eq!(emu.pc, 0x10);
+        assert_eq!(emu.word_at(0x1FFE), 0x101);
+        assert!(!emu.int_enable);
+    }
+
+    #[test]
+    fn rst_keeps_interrupts_enabled() {
+        let mut emu = setup();
+        emu.load_at(0x100, &[0xfb, 0xff]); // EI; RST 7
+        emu.pc = 0x100;
+        emu.sp = 0x2000;
+        emu.step();
+        emu.step();
+        assert_eq!(emu.pc, 0x38);
+        assert!(emu.int_enable);
+    }
+
+    #[test]
+    fn interrupt_with_bus_opcode() {
+        let mut emu = setup();
+        emu.pc = 0x1234;
+        emu.sp = 0x2000;
+        emu.load_at(0x1234, &[0x00]);
+        emu.interrupt(BusInstruction::new(&[0xcf])); // RST 1
+        assert!(emu.interrupt_pending());
+        assert_eq!(emu.step(), 4);
+        assert_eq!(emu.pc, 0x1235);
+        emu.int_enable = true;
+        assert_eq!(emu.step(), 11);
+        assert_eq!(emu.pc, 0x08);
+        assert_eq!(emu.word_at(0x1FFE), 0x1235);
+        assert!(!emu.int_enable);
+        assert!(!emu.interrupt_pending());
+    }
+
+    #[test]
+    fn interrupt_with_bus_call() {
+        let mut emu = setup();
+        emu.pc = 0x1234;
+        emu.sp = 0x2000;
+        emu.int_enable = true;
+        emu.interrupt(BusInstruction::call(0x0800));
+        assert_eq!(emu.step(), 17);
+        assert_eq!(emu.pc, 0x0800);
+        assert_eq!(emu.word_at(0x1FFE), 0x1234);
+    }
+
+    #[test]
+    fn interrupt_source_polled() {
+        let mut emu = Emu8080::with_interrupts(
+            DefaultHandler,
+            FlatMemory::default(),
+            RstInterrupts::default(),
+        );
+        emu.load_at(0x100, &[0x00, 0x00]);
+        emu.pc = 0x100;
+        emu.sp = 0x2000;
+        emu.interrupts.request(2);
+        emu.step();
+        assert_eq!(emu.pc, 0x101);
+        emu.int_enable = true;
+        assert_eq!(emu.step(), 11);
+        assert_eq!(emu.pc, 0x10);
+        assert_eq!(emu.word_at(0x1FFE), 0x101);
+        assert!(!emu.interrupts.pending());
+    }
+
+    #[test]
+    fn halt_woken_by_interrupt() {
+        let mut emu = setup();
+        emu.load_at(0x100, &[0xfb, 0x76, 0x00]); // EI; HLT; NOP
+        emu.pc = 0x100;
+        emu.sp = 0x2000;
+        emu.step();
+        assert_eq!(emu.step(), 7);
+        assert!(emu.is_halted());
+        assert_eq!(emu.try_step(), Ok(StepOutcome { cycles: 7 }));
+        assert_eq!(emu.pc, 0x102);
+        emu.generate_interrupt(1);
+        assert_eq!(emu.step(), 11);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.pc, 0x08);
+        assert_eq!(emu.word_at(0x1FFE), 0x102);
+    }
+
+    #[test]
+    fn ei_delays_interrupts() {
+        let mut emu = setup();
+        emu.load_at(0x100, &[0xcd, 0x00, 0x02, 0x00]); // CALL $0200; NOP
+        emu.load_at(0x200, &[0xfb, 0xc9]); // EI; RET
+        emu.pc = 0x100;
+        emu.sp = 0x2000;
+        emu.step();
+        emu.generate_interrupt(1);
+        emu.step();
+        assert!(emu.int_enable);
+        assert!(emu.interrupt_pending());
+        // RET completes before the interrupt is accepted
+        assert_eq!(emu.step(), 10);
+        assert_eq!(emu.pc, 0x103);
+        assert_eq!(emu.sp, 0x2000);
+        assert_eq!(emu.step(), 11);
+        assert_eq!(emu.pc, 0x08);
+        assert_eq!(emu.word_at(0x1FFE), 0x103);
+    }
+
+    #[test]
+    fn try_step_stack_wraparound() {
+        let mut emu = setup();
+        emu.load_at(0, &[0xc5]); // PUSH B
+        emu.strict_stack = true;
+        emu.sp = 1;
+        emu.b = 0x12;
+        emu.c = 0x34;
+        assert_eq!(emu.try_step(), Err(StepError::StackWraparound { sp: 1 }));
+        assert_eq!(emu.sp, 0xFFFF);
+        assert_eq!(emu.memory[0xFFFF], 0x34);
+        assert_eq!(emu.cycles, 11);
+    }
+
+    #[test]
+    fn run_counts_stack_wraparound() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x00, 0xc5]); // NOP; PUSH B
+        emu.strict_stack = true;
+        emu.sp = 1;
+        assert_eq!(
+            emu.run_cycles(1000),
+            RunOutcome {
+                cycles: 4 + 11,
+                stop: StopReason::Error(StepError::StackWraparound { sp: 1 })
+            }
+        );
+        assert_eq!(emu.cycles, 4 + 11);
+    }
+
+    #[test]
+    fn stack_wraps_around() {
+        let mut emu = setup();
+        // LXI SP,0; PUSH B; XTHL; POP D
+        emu.load_at(0, &[0x31, 0x00, 0x00, 0xc5, 0xe3, 0xd1]);
+        emu.b = 0x12;
+        emu.c = 0x34;
+        emu.h = 0x56;
+        emu.l = 0x78;
+        assert_eq!(emu.run_cycles(10 + 11 + 18 + 10).stop, StopReason::Budget);
+        assert_eq!(emu.sp, 0);
+        assert_eq!(emu.hl(), 0x1234);
+        assert_eq!((emu.d, emu.e), (0x56, 0x78));
+    }
+
+    #[test]
+    fn xthl_keeps_stack_pointer() {
+        let mut emu = setup();
+        emu.load_at(0, &[0xe3]); // XTHL
+        emu.load_at(0xFFFE, &[0x34, 0x12]);
+        emu.strict_stack = true;
+        emu.sp = 0xFFFE;
+        emu.h = 0x56;
+        emu.l = 0x78;
+        assert_eq!(emu.try_step(), Ok(StepOutcome { cycles: 18 }));
+        assert_eq!(emu.sp, 0xFFFE);
+        assert_eq!(emu.hl(), 0x1234);
+        assert_eq!(emu.word_at(0xFFFE), 0x5678);
+    }
+
+    #[test]
+    fn try_step_pc_out_of_memory() {
+        let mut emu = setup();
+        emu.pc = 0x10000;
+        assert_eq!(
+            emu.try_step(),
+            Err(StepError::PcOutOfMemory { pc: 0x10000 })
+        );
+    }
+
+    #[test]
+    fn try_step_rom_write() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x32, 0x00, 0x10]); // STA $1000
+        emu.protect(0x1000..0x2000, RomPolicy::Error);
+        emu.a = 0x42;
+        let err = emu.try_step().unwrap_err();
+        assert_eq!(
+            err,
+            StepError::RomWrite(RomWrite {
+                addr: 0x1000,
+                val: 0x42,
+                pc: 0
+            })
+        );
+    }
+
+    #[test]
+    fn sbb_with_borrow() {
+        let mut emu = setup();
+        emu.a = 0x04;
+        emu.fl.cy = true;
+        emu.sbb(0x02);
+        assert_eq!(emu.a, 0x01);
+        assert!(!emu.fl.cy);
+        assert!(emu.fl.ac);
+        emu.sbb(0x01);
+        assert_eq!(emu.a, 0x00);
+        assert!(emu.fl.z);
+        emu.fl.cy = true;
+        emu.sbb(0x00);
+        assert_eq!(emu.a, 0xff);
+        assert!(emu.fl.cy);
+        assert!(!emu.fl.ac);
+    }
+
+    #[test]
+    fn cmp_flags() {
+        let mut emu = setup();
+        emu.a = 0x0a;
+        emu.cmp(0x05);
+        assert_eq!(emu.a, 0x0a);
+        assert!(!emu.fl.cy);
+        assert!(!emu.fl.z);
+        assert!(emu.fl.ac);
+        emu.cmp(0x0b);
+        assert!(emu.fl.cy);
+        assert!(!emu.fl.ac);
+    }
+
+    #[test]
+    fn logical_aux_carry() {
+        let mut emu = setup();
+        emu.a = 0x08;
+        emu.fl.cy = true;
+        emu.and(0x01);
+        assert!(emu.fl.ac);
+        assert!(!emu.fl.cy);
+        assert!(emu.fl.z);
+        emu.a = 0x0f;
+        emu.fl.ac = true;
+        emu.or(0x0f);
+        assert!(!emu.fl.ac);
+        emu.xor(0x0f);
+        assert!(!emu.fl.ac);
+        assert!(emu.fl.z);
+    }
+
+    #[test]
+    fn inr_dcr_aux_carry() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x3c, 0x3d, 0x3d]); // INR A; DCR A; DCR A
+        emu.a = 0x0f;
+        emu.fl.cy = true;
+        emu.step();
+        assert_eq!(emu.a, 0x10);
+        assert!(emu.fl.ac);
+        emu.step();
+        assert_eq!(emu.a, 0x0f);
+        assert!(!emu.fl.ac);
+        emu.step();
+        assert_eq!(emu.a, 0x0e);
+        assert!(emu.fl.ac);
+        assert!(emu.fl.cy);
+    }
+
+    #[test]
+    fn add_register_aux_carry() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x80, 0x88]); // ADD B; ADC B
+        emu.a = 0x0f;
+        emu.b = 0xf1;
+        emu.step();
+        assert_eq!(emu.a, 0x00);
+        assert!(emu.fl.cy);
+        assert!(emu.fl.ac);
+        assert!(emu.fl.z);
+        emu.step();
+        assert_eq!(emu.a, 0xf2);
+        assert!(!emu.fl.cy);
+        assert!(!emu.fl.ac);
+    }
+
+    /// Every ALU operation on every pair of operands, against the 8080
+    /// flags worked out on wider integers.
+    #[test]
+    fn alu_flags_exhaustive() {
+        let mut emu = setup();
+        for op in 0..8u8 {
+            for a in 0..=255u8 {
+                for val in 0..=255u8 {
+                    for &cy in &[false, true] {
+                        let (lhs, rhs) = (i32::from(a), i32::from(val));
+                        let carry = i32::from(cy && (op == 1 || op == 3));
+                        let (ans, ac) = match op {
+                            0 | 1 => (lhs + rhs + carry, (lhs & 15) + (rhs & 15) + carry > 15),
+                            2 | 3 | 7 => (lhs - rhs - carry, (lhs & 15) - (rhs & 15) - carry >= 0),
+                            4 => (lhs & rhs, (lhs | rhs) & 8 != 0),
+                            5 => (lhs ^ rhs, false),
+                            _ => (lhs | rhs, false),
+                        };
+                        let result = ans as u8;
+                        emu.a = a;
+                        emu.fl.cy = cy;
+                        emu.alu(op << 3, val);
+                        let msg = format!("op {} with A={:02X}, {:02X}, CY={}", op, a, val, cy);
+                        assert_eq!(emu.a, if op == 7 { a } else { result }, "{}", msg);
+                        assert_eq!(emu.fl.cy, !(0..=255).contains(&ans), "CY of {}", msg);
+                        assert_eq!(emu.fl.ac, ac, "AC of {}", msg);
+                        assert_eq!(emu.fl.z, result == 0, "Z of {}", msg);
+                        assert_eq!(emu.fl.s, result & 0x80 != 0, "S of {}", msg);
+                        assert_eq!(emu.fl.p, result.count_ones() & 1 == 0, "P of {}", msg);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn daa() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x27, 0x27]); // DAA; DAA
+        emu.a = 0x9b;
+        emu.step();
+        assert_eq!(emu.a, 0x01);
+        assert!(emu.fl.cy);
+        assert!(emu.fl.ac);
+        emu.a = 0x15 + 0x27;
+        emu.fl.cy = false;
+        emu.fl.ac = false;
+        emu.step();
+        assert_eq!(emu.a, 0x42);
+        assert!(!emu.fl.cy);
+    }
+
+    #[test]
+    fn push_pop_psw() {
+        let mut emu = setup();
+        emu.load_at(0, &[0xf5, 0xc1, 0xc5, 0xf1]); // PUSH PSW; POP B; PUSH B; POP PSW
+        emu.sp = 0x2000;
+        emu.a = 0x42;
+        emu.step();
+        emu.step();
+        assert_eq!(emu.b, 0x42);
+        assert_eq!(emu.c, 0x02);
+        emu.c = 0xff;
+        emu.step();
+        emu.step();
+        assert!(emu.fl.s && emu.fl.z && emu.fl.ac && emu.fl.p && emu.fl.cy);
+        assert_eq!(emu.fl.to_psw(), 0xd7);
+    }
+
+    #[test]
+    fn rom_write_ignored() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x32, 0x00, 0x10]); // STA $1000
+        emu.protect(0x1000..0x2000, RomPolicy::Ignore);
+        emu.a = 0x42;
+        emu.step();
+        assert_eq!(emu.memory[0x1000], 0xFF);
+    }
+
+    #[test]
+    #[should_panic(expected = "Write of 42 to ROM at 1000")]
+    fn rom_write_error() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x32, 0x00, 0x10]); // STA $1000
+        emu.protect(0x1000..0x2000, RomPolicy::Error);
+        emu.a = 0x42;
+        emu.step();
+    }
+
+    #[test]
+    fn run_cycles_budget() {
+        let mut emu = setup();
+        emu.load_at(0, &[0xc3, 0x00, 0x00]); // JMP 0
+        assert_eq!(
+            emu.run_cycles(25),
+            RunOutcome {
+                cycles: 30,
+                stop: StopReason::Budget
+            }
+        );
+    }
+
+    #[test]
+    fn run_until_breakpoint() {
+        let mut emu = setup();
+        emu.load_at(0, &[0x00, 0x00, 0x00, 0xc3, 0x00, 0x00]); // NOP; NOP; NOP; JMP 0
+        let outcome = emu.run_until(1000, |state| state.pc == 2);
+        assert_eq!(outcome.stop, StopReason::Breakpoint);
+        assert_eq!(outcome.cycles, 8);
+        // Resumes past the breakpoint
+        let outcome = emu.run_until(1000, |state| state.pc == 2);
+        assert_eq!(outcome.stop, StopReason::Breakpoint);
+        assert_eq!(outcome.cycles, 4 + 10 + 8);
+    }
+
+    #[test]
+    fn run_stops_on_halt() {
+        let mut emu = setup();
+        emu.load_at(0, &[0xfb, 0x00, 0x76]); // EI; NOP; HLT
+        let outcome = emu.run_cycles(1000);
+        assert_eq!(outcome.stop, StopReason::Halted);
+        assert_eq!(outcome.cycles, 15);
+        // Idles until an interrupt
+        assert_eq!(emu.run_cycles(70).stop, StopReason::Budget);
+        assert!(emu.is_halted());
+
+        emu.int_enable = false;
+        assert_eq!(
+            emu.run_cycles(1000).stop,
+            StopReason::Error(StepError::Halted { pc: 2 })
+        );
+    }
+
+    #[test]
+    fn run_frame_interrupts() {
+        let mut emu = setup();
+        emu.sp = 0x2000;
+        emu.load_at(0, &[0xfb, 0x76]); // EI; HLT
+        emu.load_at(0x08, &[0x3c, 0xfb, 0xc9]); // INR A; EI; RET
+        emu.load_at(0x10, &[0x04, 0xfb, 0xc9]); // INR B; EI; RET
+        let outcome = emu.run_frame(1000, &[1, 2]);
+        assert_eq!(outcome.stop, StopReason::Budget);
+        assert!(outcome.cycles >= 1000);
+        assert_eq!((emu.a, emu.b), (1, 0));
+        // RST 2 is accepted at the start of the next frame
+        emu.run_frame(1000, &[1, 2]);
+        assert_eq!((emu.a, emu.b), (2, 1));
+    }
+
+    #[derive(Default)]
+    struct RecordingHandler {
+        accesses: Vec<(u8, u64)>,
+    }
+
+    impl InOutHandler for RecordingHandler {
+        fn read(&mut self, port: u8, cycles: u64) -> u8 {
+            self.accesses.push((port, cycles));
+            0
+        }
+
+        fn write(&mut self, port: u8, _val: u8, cycles: u64) {
+            self.accesses.push((port, cycles));
+        }
+    }
+
+    #[test]
+    fn io_timestamps() {
+        let mut emu = Emu8080::new(RecordingHandler::default());
+        emu.load_at(0, &[0x00, 0xd3, 0x01, 0xdb, 0x02]); // NOP; OUT 1; IN 2
+        emu.run_cycles(24);
+        assert_eq!(emu.cycles, 24);
+        assert_eq!(emu.io.accesses, vec![(1, 4), (2, 14)]);
+    }
+
+    #[test]
+    fn scheduled_events() {
+        use std::sync::{Arc, Mutex};
+
+        let mut emu = setup();
+        emu.load_at(0, &[0xc3, 0x00, 0x00]); // JMP 0
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let log = fired.clone();
+        emu.schedule(25, move |emu, at| {
+            log.lock().unwrap().push((at, emu.cycles));
+            None
+        });
+        let log = fired.clone();
+        emu.schedule(0, move |emu, at| {
+            log.lock().unwrap().push((at, emu.cycles));
+            Some(at + 40)
+        });
+        emu.run_cycles(100);
+        assert_eq!(
+            *fired.lock().unwrap(),
+            vec![(0, 0), (25, 30), (40, 40), (80, 80)]
+        );
+        assert_eq!(emu.events.next_due(), Some(120));
+    }
+
+    #[test]
+    fn scheduled_event_drift() {
+        use std::sync::{Arc, Mutex};
+
+        let mut emu = setup();
+        emu.load_at(0, &[0xc3, 0x00, 0x00]); // JMP 0
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let log = fired.clone();
+        emu.schedule(25, move |emu, at| {
+            log.lock().unwrap().push(emu.cycles - at);
+            Some(at + 25)
+        });
+        emu.run_cycles(1000);
+        // Late by what is left of the JMP running at each cycle, no more
+        let fired = fired.lock().unwrap();
+        assert_eq!(fired.len(), 40);
+        assert!(fired.iter().all(|&late| late < 10));
+        assert_eq!(fired[..4], [5, 0, 5, 0]);
+    }
+
+    #[test]
+    fn emulator_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Emu8080>();
+    }
+
+    #[test]
+    fn undocumented_aliases() {
+        let mut emu = setup();
+        emu.sp = 0x2000;
+        emu.load_at(0, &[0x08, 0xcb, 0x00, 0x01]); // NOP; JMP $0100
+        emu.load_at(0x100, &[0xdd, 0x00, 0x02]); // CALL $0200
+        emu.load_at(0x200, &[0xd9]); // RET
+        assert_eq!(emu.step(), 4);
+        assert_eq!(emu.step(), 10);
+        assert_eq!(emu.pc, 0x100);
+        assert_eq!(emu.step(), 17);
+        assert_eq!(emu.pc, 0x200);
+        assert_eq!(emu.step(), 10);
+        assert_eq!(emu.pc, 0x103);
+        assert_eq!(emu.sp, 0x2000);
+    }
+
+    #[test]
+    fn strict_mode_rejects_undocumented() {
+        let mut emu = setup();
+        emu.strict = true;
+        emu.load_at(0, &[0x00, 0xed, 0x00, 0x01]); // NOP; CALL $0100
+        emu.try_step().unwrap();
+        assert_eq!(
+            emu.try_step(),
+            Err(StepError::IllegalOpcode {
+                opcode: 0xed,
+                pc: 1
+            })
+        );
+        assert_eq!(emu.pc, 1);
+        assert_eq!(emu.cycles, 4);
+    }
+
+    #[test]
+    fn run_cycles_match_across_errors() {
+        let mut emu = setup();
+        emu.strict = true;
+        emu.sp = 0x2000;
+        emu.load_at(0, &[0xc5, 0x00, 0xed]); // PUSH B; NOP; CALL
+        let first = emu.run_cycles(1000);
+        assert_eq!(
+            first.stop,
+            StopReason::Error(StepError::IllegalOpcode {
+                opcode: 0xed,
+                pc: 2
+            })
+        );
+        emu.strict = false;
+        emu.int_enable = false;
+        emu.load_at(2, &[0x76]); // HLT
+        let second = emu.run_cycles(1000);
+        assert_eq!(second.stop, StopReason::Error(StepError::Halted { pc: 2 }));
+        let third = emu.run_cycles(1000);
+        assert_eq!(third.stop, StopReason::Error(StepError::Halted { pc: 2 }));
+        assert_eq!(first.cycles + second.cycles + third.cycles, 11 + 4 + 7 + 7);
+        assert_eq!(emu.cycles, 11 + 4 + 7 + 7);
+    }
+
+    #[derive(Default)]
+    struct SerialHandler {
+        sid: bool,
+        sod: Vec<(bool, u64)>,
+    }
+
+    impl InOutHandler for SerialHandler {
+        fn read(&mut self, _port: u8, _cycles: u64) -> u8 {
+            0
+        }
+
+        fn write(&mut self, _port: u8, _val: u8, _cycles: u64) {}
+
+        fn sid(&mut self, _cycles: u64) -> bool {
+            self.sid
+        }
+
+        fn sod(&mut self, level: bool, cycles: u64) {
+            self.sod.push((level, cycles));
+        }
+    }
+
+    fn setup_8085() -> Emu8080<SerialHandler> {
+        let mut emu = Emu8080::with_model(
+            SerialHandler::default(),
+            FlatMemory::default(),
+            NoInterrupts,
+            Model::I8085,
+        );
+        emu.sp = 0x1000;
+        emu
+    }
+
+    #[test]
+    fn i8085_rim_sim() {
+        let mut emu = setup_8085();
+        emu.load_at(
+            0,
+            &[
+                0x3e, 0xcd, // MVI A,CDh: SOD=1, SOE, MSE, mask 5.5 and 7.5
+                0x30, // SIM
+                0xfb, // EI
+                0x20, // RIM
+            ],
+        );
+        emu.io.sid = true;
+        emu.i8085.rst65 = true;
+        emu.i8085.rst75();
+        for _ in 0..4 {
+            emu.step();
+        }
+        assert_eq!(emu.i8085.mask(), 0x05);
+        assert_eq!(emu.io.sod, vec![(true, 7)]);
+        // RST 6.5 is unmasked but still delayed by EI
+        assert_eq!(emu.a, 0b1110_1101);
+        assert_eq!(emu.pc, 5);
+    }
+
+    #[test]
+    fn i8085_interrupt_priority() {
+        let mut emu = setup_8085();
+        emu.load_at(0, &[0xfb, 0x00, 0x00]); // EI; NOP; NOP
+        emu.int_enable = true;
+        emu.i8085.rst55 = true;
+        emu.i8085.rst65 = true;
+        emu.i8085.rst75();
+        emu.generate_interrupt(1);
+        assert_eq!(emu.step(), 12);
+        assert_eq!(emu.pc, 0x3c);
+        assert_eq!(emu.sp, 0x0ffe);
+        assert!(!emu.int_enable);
+
+        // EI, then NOP before interrupts are accepted again
+        let reenable = |emu: &mut Emu8080<SerialHandler>| {
+            emu.pc = 0;
+            for _ in 0..3 {
+                emu.step();
+            }
+        };
+        reenable(&mut emu);
+        assert_eq!(emu.pc, 0x34);
+
+        // Levels are held until the device releases them
+        emu.i8085.rst65 = false;
+        reenable(&mut emu);
+        assert_eq!(emu.pc, 0x2c);
+
+        emu.i8085.rst55 = false;
+        reenable(&mut emu);
+        assert_eq!(emu.pc, 0x08);
+    }
+
+    #[test]
+    fn i8085_trap_wakes_halt() {
+        let mut emu = setup_8085();
+        emu.load_at(0, &[0xfb, 0x76]); // EI; HLT
+        emu.load_at(0x24, &[0x20]); // RIM
+        emu.step();
+        assert_eq!(emu.step(), 5);
+        assert!(emu.is_halted());
+        assert_eq!(emu.try_step(), Ok(StepOutcome { cycles: 5 }));
+
+        emu.i8085.trap();
+        emu.step();
+        assert_eq!(emu.pc, 0x24);
+        assert!(!emu.is_halted());
+        assert!(!emu.int_enable);
+        // RIM reports the interrupt enable flag from before TRAP
+        emu.step();
+        assert_eq!(emu.a & 0x08, 0x08);
+    }
+
+    #[test]
+    fn i8085_halt_with_interrupts_disabled() {
+        let mut emu = setup_8085();
+        emu.load_at(0, &[0x76]); // HLT
+        emu.step();
+        assert_eq!(emu.try_step(), Ok(StepOutcome { cycles: 5 }));
+        emu.i8085.trap();
+        emu.step();
+        assert_eq!(emu.pc, 0x24);
+    }
+
+    #[test]
+    fn i8085_undocumented() {
+        let mut emu = setup_8085();
+        emu.load_at(
+            0,
+            &[
+                0x08, // DSUB
+                0x10, // ARHL
+                0x28, 0x10, // LDHI 10h
+                0xd9, // SHLX
+                0x23, // INX H
+                0xed, // LHLX
+                0xdd, 0x00, 0x02, // JNK $0200
+            ],
+        );
+        emu.set_long(0x20, (0x00, 0x10)); // HL = 1000h
+        emu.set_long(0x00, (0x01, 0x20)); // BC = 2001h
+        emu.step();
+        assert_eq!(emu.hl(), 0xefff);
+        assert!(emu.fl.cy);
+        assert!(!emu.fl.z);
+        emu.step();
+        assert_eq!(emu.hl(), 0xf7ff);
+        assert!(emu.fl.cy);
+        emu.step();
+        assert_eq!(emu.de(), 0xf80f);
+        emu.step();
+        assert_eq!((emu.memory[0xf80f], emu.memory[0xf810]), (0xff, 0xf7));
+        emu.step();
+        emu.step();
+        assert_eq!(emu.hl(), 0xf7ff);
+        assert!(!emu.fl.k);
+        assert_eq!(emu.step(), 10);
+        assert_eq!(emu.pc, 0x200);
+    }
+
+    #[test]
+    fn i8085_flags() {
+        let mut emu = setup_8085();
+        emu.load_at(
+            0,
+            &[
+                0x3e, 0x70, // MVI A,70h
+                0xc6, 0x20, // ADI 20h
+                0xe6, 0x00, // ANI 0
+                0xcb, // RSTV
+                0xf5, // PUSH PSW
+            ],
+        );
+        emu.step();
+        emu.step();
+        assert!(emu.fl.v && emu.fl.s && !emu.fl.k);
+        emu.step();
+        // AND always sets AC on the 8085, and leaves V alone
+        assert!(emu.fl.ac && emu.fl.v);
+        assert_eq!(emu.step(), 12);
+        assert_eq!(emu.pc, 0x40);
+
+        emu.pc = 7;
+        emu.step();
+        assert_eq!(emu.memory[emu.sp], 0b0101_0110);
+    }
+
+    #[test]
+    fn i8085_strict_mode() {
+        let mut emu = setup_8085();
+        emu.strict = true;
+        emu.load_at(0, &[0x20, 0x08]); // RIM; DSUB
+        emu.try_step().unwrap();
+        assert_eq!(
+            emu.try_step(),
+            Err(StepError::IllegalOpcode {
+                opcode: 0x08,
+                pc: 1
+            })
+        );
+    }
+
+    fn setup_z80() -> Emu8080 {
+        let mut emu = Emu8080::with_model(
+            DefaultHandler,
+            FlatMemory::default(),
+            NoInterrupts,
+            Model::Z80,
+        );
+        emu.sp = 0x1000;
+        emu
+    }
+
+    #[test]
+    fn z80_relative_jumps() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0x06, 0x03, // LD B,3
+                0x3c, // loop: INC A
+                0x10, 0xfd, // DJNZ loop
+                0x18, 0x02, // JR +2
+                0x00, 0x00, // skipped
+                0x28, 0xfe, // JR Z,$
+            ],
+        );
+        emu.step();
+        let cycles: Vec<_> = (0..6).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![4, 13, 4, 13, 4, 8]);
+        assert_eq!((emu.a, emu.b), (3, 0));
+        assert_eq!(emu.step(), 12);
+        assert_eq!(emu.pc, 9);
+        assert_eq!(emu.step(), 7);
+        assert_eq!(emu.pc, 11);
+    }
+
+    #[test]
+    fn z80_exchanges() {
+        let mut emu = setup_z80();
+        emu.load_at(0, &[0x08, 0xd9, 0x08]); // EX AF,AF'; EXX; EX AF,AF'
+        emu.a = 0x12;
+        emu.fl.cy = true;
+        emu.set_long(0x20, (0x34, 0x12));
+        emu.z80.af_alt = 0x5640;
+        emu.z80.hl_alt = 0xbeef;
+        emu.step();
+        assert_eq!(emu.a, 0x56);
+        assert!(emu.fl.z && !emu.fl.cy);
+        assert_eq!(emu.z80.af_alt, 0x1201);
+        emu.step();
+        assert_eq!((emu.hl(), emu.z80.hl_alt), (0xbeef, 0x1234));
+        emu.step();
+        assert_eq!(emu.a, 0x12);
+        assert!(emu.fl.cy);
+    }
+
+    #[test]
+    fn z80_flags() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0x3e, 0x7f, // LD A,7Fh
+                0x3c, // INC A
+                0xd6, 0x01, // SUB 1
+                0xe6, 0x0f, // AND 0Fh
+                0x3e, 0x15, // LD A,15h
+                0xd6, 0x06, // SUB 6
+                0x27, // DAA
+                0xed, 0x44, // NEG
+            ],
+        );
+        emu.step();
+        emu.step();
+        // P/V is overflow after arithmetic
+        assert!(emu.fl.p && emu.fl.s && emu.fl.ac && !emu.fl.n);
+        emu.step();
+        assert_eq!(emu.a, 0x7f);
+        assert!(emu.fl.p && emu.fl.n && emu.fl.ac && !emu.fl.cy);
+        emu.step();
+        // and parity after logic
+        assert!(emu.fl.ac && emu.fl.p && !emu.fl.n);
+        emu.step();
+        emu.step();
+        emu.step();
+        assert_eq!(emu.a, 0x09);
+        assert_eq!(emu.step(), 8);
+        assert_eq!(emu.a, 0xf7);
+        assert!(emu.fl.cy && emu.fl.n && emu.fl.s);
+        assert_eq!(emu.fl.to_psw_z80(), 0b1001_0011);
+    }
+
+    #[test]
+    fn z80_index_registers() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0xdd, 0x21, 0x00, 0x20, // LD IX,2000h
+                0xfd, 0x21, 0x10, 0x20, // LD IY,2010h
+                0xdd, 0x36, 0x05, 0x42, // LD (IX+5),42h
+                0xfd, 0x7e, 0xf5, // LD A,(IY-11)
+                0xdd, 0x34, 0x05, // INC (IX+5)
+                0xdd, 0x86, 0x05, // ADD A,(IX+5)
+                0xdd, 0x66, 0x05, // LD H,(IX+5)
+                0xdd, 0x2c, // INC IXL
+                0xdd, 0xe5, // PUSH IX
+                0xdd, 0xeb, // EX DE,HL
+                0xdd, 0xcb, 0x04, 0xfe, // SET 7,(IX+4)
+                0xfd, 0xcb, 0xf5, 0x7e, // BIT 7,(IY-11)
+            ],
+        );
+        let cycles: Vec<_> = (0..12).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![14, 14, 19, 19, 23, 19, 19, 8, 15, 8, 23, 20]);
+        assert_eq!(emu.z80.ix, 0x2001);
+        assert_eq!(emu.z80.iy, 0x2010);
+        assert_eq!(emu.a, 0x85);
+        assert_eq!(emu.memory[0x2005], 0xc3);
+        assert_eq!(emu.word_at(0x0ffe), 0x2001);
+        assert_eq!(emu.de(), 0x4300);
+        assert!(!emu.fl.z && emu.fl.s);
+        assert_eq!(emu.pc, 38);
+    }
+
+    #[test]
+    fn z80_block_instructions() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0x21, 0x00, 0x20, // LD HL,2000h
+                0x11, 0x00, 0x30, // LD DE,3000h
+                0x01, 0x03, 0x00, // LD BC,3
+                0xed, 0xb0, // LDIR
+                0x21, 0x00, 0x20, // LD HL,2000h
+                0x01, 0x03, 0x00, // LD BC,3
+                0x3e, 0x22, // LD A,22h
+                0xed, 0xb1, // CPIR
+            ],
+        );
+        emu.load_at(0x2000, &[0x11, 0x22, 0x33]);
+        for _ in 0..3 {
+            emu.step();
+        }
+        let cycles: Vec<_> = (0..3).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![21, 21, 16]);
+        assert_eq!(emu.memory[0x3000..0x3003], [0x11, 0x22, 0x33]);
+        assert_eq!((emu.hl(), emu.de(), emu.bc()), (0x2003, 0x3003, 0));
+        assert!(!emu.fl.p);
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(emu.step(), 21);
+        assert_eq!(emu.step(), 16);
+        assert_eq!((emu.hl(), emu.bc(), emu.pc), (0x2002, 1, 21));
+        assert!(emu.fl.z && emu.fl.p);
+    }
+
+    /// DAA after adding or subtracting every pair of BCD numbers, with the
+    /// N and H flags left by each.
+    #[test]
+    fn z80_daa() {
+        let mut emu = setup_z80();
+        let bcd = |n: u8| (n / 10) * 16 + n % 10;
+        for x in 0..100u8 {
+            for y in 0..100u8 {
+                for &(op, n) in &[(0x80, false), (0x90, true)] {
+                    emu.load_at(0, &[op, 0x27]); // ADD A,B or SUB B; DAA
+                    emu.pc = 0;
+                    emu.a = bcd(x);
+                    emu.b = bcd(y);
+                    emu.step();
+                    assert_eq!(emu.fl.n, n);
+                    emu.step();
+                    let (expected, carry) = if n {
+                        ((x + 100 - y) % 100, x < y)
+                    } else {
+                        ((x + y) % 100, x + y >= 100)
+                    };
+                    let msg = format!("{:02} {} {:02}", x, if n { '-' } else { '+' }, y);
+                    assert_eq!(emu.a, bcd(expected), "{}", msg);
+                    assert_eq!(emu.fl.cy, carry, "CY of {}", msg);
+                    assert_eq!(emu.fl.n, n, "N of {}", msg);
+                    assert_eq!(emu.fl.z, expected == 0, "Z of {}", msg);
+                }
+            }
+        }
+        // H after DAA is the carry or borrow out of bit 3 of the adjustment
+        emu.load_at(0, &[0x27]);
+        for &(a, n, h, result, half) in &[
+            (0x0f, true, true, 0x09, false),  // 10h - 1
+            (0x1a, false, false, 0x20, true), // 9 + 11h
+            (0x11, false, true, 0x17, false), // 9 + 8
+        ] {
+            emu.pc = 0;
+            emu.a = a;
+            emu.fl.n = n;
+            emu.fl.ac = h;
+            emu.fl.cy = false;
+            emu.step();
+            assert_eq!((emu.a, emu.fl.ac), (result, half), "DAA of {:02X}", a);
+        }
+    }
+
+    #[test]
+    fn z80_block_repeats() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0x21, 0x02, 0x20, // LD HL,2002h
+                0x11, 0x03, 0x20, // LD DE,2003h
+                0x01, 0x03, 0x00, // LD BC,3
+                0xed, 0xb8, // LDDR
+                0x21, 0x00, 0x20, // LD HL,2000h
+                0x01, 0x02, 0x00, // LD BC,2
+                0x3e, 0x99, // LD A,99h
+                0xed, 0xb1, // CPIR
+                0xed, 0xb9, // CPDR
+            ],
+        );
+        emu.load_at(0x2000, &[0x11, 0x22, 0x33]);
+        for _ in 0..3 {
+            emu.step();
+        }
+        // Copying downwards shifts the overlapping block up
+        let cycles: Vec<_> = (0..3).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![21, 21, 16]);
+        assert_eq!(emu.memory[0x2000..0x2004], [0x11, 0x11, 0x22, 0x33]);
+        assert_eq!((emu.hl(), emu.de(), emu.bc()), (0x1fff, 0x2000, 0));
+        assert!(!emu.fl.p && !emu.fl.n);
+        for _ in 0..3 {
+            emu.step();
+        }
+        // CPIR stops when BC runs out without a match
+        let cycles: Vec<_> = (0..2).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![21, 16]);
+        assert_eq!((emu.hl(), emu.bc(), emu.pc), (0x2002, 0, 21));
+        assert!(!emu.fl.z && !emu.fl.p && emu.fl.n);
+        // With BC at 0, CPDR goes on through the 64K wraparound of BC
+        emu.load_at(0x2001, &[0x99]);
+        assert_eq!(emu.step(), 21);
+        assert_eq!(emu.step(), 16);
+        assert_eq!((emu.hl(), emu.bc(), emu.pc), (0x2000, 0xfffe, 23));
+        assert!(emu.fl.z && emu.fl.p);
+    }
+
+    #[test]
+    fn z80_index_displacements() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0xdd, 0x21, 0x80, 0x20, // LD IX,2080h
+                0xfd, 0x21, 0xf0, 0xff, // LD IY,0FFF0h
+                0xdd, 0x77, 0x80, // LD (IX-128),A
+                0xdd, 0x46, 0x7f, // LD B,(IX+127)
+                0xfd, 0x70, 0x7f, // LD (IY+127),B
+                0xdd, 0xcb, 0xff, 0x16, // RL (IX-1)
+                0xfd, 0xcb, 0x7f, 0x0e, // RRC (IY+127)
+            ],
+        );
+        emu.a = 0x5a;
+        emu.memory[0x20ff] = 0x81;
+        emu.memory[0x207f] = 0x80;
+        let cycles: Vec<_> = (0..7).map(|_| emu.step()).collect();
+        assert_eq!(cycles, vec![14, 14, 19, 19, 19, 23, 23]);
+        assert_eq!(emu.memory[0x2000], 0x5a);
+        assert_eq!(emu.b, 0x81);
+        // IY+d wraps around the address space
+        assert_eq!(emu.memory[0x006f], 0xc0);
+        assert_eq!(emu.memory[0x207f], 0x00);
+        assert!(emu.fl.cy);
+        assert_eq!(emu.pc, 25);
+    }
+
+    #[test]
+    fn z80_16_bit_arithmetic() {
+        let mut emu = setup_z80();
+        emu.load_at(
+            0,
+            &[
+                0x21, 0x00, 0x80, // LD HL,8000h
+                0x01, 0x01, 0x00, // LD BC,1
+                0xb7, // OR A
+                0xed, 0x42, // SBC HL,BC
+                0xed, 0x4a, // ADC HL,BC
+                0xed, 0x43, 0x00, 0x20, // LD (2000h),BC
+                0xed, 0x5b, 0x00, 0x20, // LD DE,(2000h)
+            ],
+        );
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(emu.step(), 15);
+        assert_eq!(emu.hl(), 0x7fff);
+        assert!(emu.fl.p && emu.fl.n && emu.fl.ac && !emu.fl.cy);
+        emu.step();
+        assert_eq!(emu.hl(), 0x8000);
+        assert!(emu.fl.p && emu.fl.s && !emu.fl.n);
+        assert_eq!(emu.step(), 20);
+        emu.step();
+        assert_eq!(emu.de(), 1);
+    }
+
+    #[test]
+    fn z80_interrupt_modes() {
+        let mut emu = setup_z80();
+        emu.load_at(0, &[0xed, 0x5e, 0xfb, 0x00, 0x00]); // IM 2; EI; NOP; NOP
+        emu.load_at(0x3020, &[0x00, 0x40]);
+        emu.z80.i = 0x30;
+        emu.interrupt(BusInstruction::new(&[0x20]));
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(emu.step(), 19);
+        assert_eq!(emu.pc, 0x4000);
+        assert!(!emu.int_enable && !emu.z80.iff2);
+
+        emu.pc = 0;
+        emu.load_at(0, &[0xed, 0x56]); // IM 1
+        emu.generate_interrupt(2);
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(emu.step(), 13);
+        assert_eq!(emu.pc, 0x38);
+    }
+
+    #[test]
+    fn z80_im2_wakes_halt() {
+        let mut emu = setup_z80();
+        emu.load_at(0, &[0xed, 0x5e, 0xfb, 0x76]); // IM 2; EI; HALT
+        emu.load_at(0x80fe, &[0x34, 0x12]);
+        emu.load_at(0x1234, &[0xed, 0x4d]); // RETI
+        emu.z80.i = 0x80;
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert!(emu.is_halted());
+        assert_eq!(emu.step(), 4);
+        // The table entry is I followed by the byte on the data bus
+        emu.interrupt(BusInstruction::new(&[0xfe]));
+        assert_eq!(emu.step(), 19);
+        assert!(!emu.is_halted());
+        assert_eq!(emu.pc, 0x1234);
+        assert_eq!(emu.word_at(0x0ffe), 4);
+        assert!(!emu.int_enable && !emu.z80.iff2);
+        assert_eq!(emu.step(), 14);
+        assert_eq!((emu.pc, emu.sp), (4, 0x1000));
+    }
+
+    #[test]
+    fn z80_nmi() {
+        let mut emu = setup_z80();
+        emu.load_at(0, &[0xfb, 0x76]); // EI; HALT
+        emu.load_at(0x66, &[0xed, 0x45]); // RETN
+        emu.step();
+        emu.step();
+        emu.z80.nmi();
+        assert_eq!(emu.step(), 11);
+        assert_eq!(emu.pc, 0x66);
+        assert!(!emu.int_enable && emu.z80.iff2);
+        assert_eq!(emu.step(), 14);
+        assert_eq!(emu.pc, 2);
+        assert!(emu.int_enable);
+    }
+
+    #[test]
+    fn z80_refresh_register() {
+        let mut emu = setup_z80();
+        emu.load_at(0, &[0x00, 0xdd, 0x00, 0xed, 0x5f]); // NOP; DD NOP; LD A,R
+        emu.z80.r = 0xfe;
+        for _ in 0..3 {
+            emu.step();
+        }
+        assert_eq!(emu.a, 0x83);
+    }
 }
@@ -0,0 +1,116 @@
+use std::ops::{Deref, DerefMut};
+
+/// The CPU's view of the 16-bit address space.
+///
+/// Every memory access performed by an instruction goes through this trait,
+/// so implementations are free to map ROM, mirror RAM, leave regions unmapped
+/// or hook memory-mapped devices.
+///
+/// CPU accesses carry the value of the CPU's cycle counter at the start of
+/// the instruction performing them, for devices that need to timestamp them.
+pub trait MemoryBus {
+    /// Reads the byte at `addr` on behalf of the CPU.
+    fn read(&mut self, addr: u16, _cycles: u64) -> u8 {
+        self.peek(addr)
+    }
+
+    /// Writes `val` at `addr` on behalf of the CPU.
+    fn write(&mut self, addr: u16, val: u8, cycles: u64);
+
+    /// Reads the byte at `addr` without side effects, for debuggers and
+    /// disassemblers.
+    fn peek(&self, addr: u16) -> u8;
+
+    /// Whether anything answers at `addr`. The CPU refuses to fetch
+    /// instructions from unmapped addresses.
+    fn is_mapped(&self, _addr: u16) -> bool {
+        true
+    }
+}
+
+/// A flat 64K RAM, the default memory of the emulator.
+pub struct FlatMemory(Vec<u8>);
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        FlatMemory(vec![0xFF; 0x10000])
+    }
+}
+
+impl MemoryBus for FlatMemory {
+    fn write(&mut self, addr: u16, val: u8, _cycles: u64) {
+        self.0[usize::from(addr)] = val;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.0[usize::from(addr)]
+    }
+}
+
+impl Deref for FlatMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for FlatMemory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A memory smaller than the address space, repeated across it.
+///
+/// Only the low bits of the address are decoded, so address `size + n` is an
+/// alias of address `n`.
+pub struct MirroredMemory {
+    data: Vec<u8>,
+    mask: usize,
+}
+
+impl MirroredMemory {
+    pub fn new(size: usize) -> Self {
+        assert!(
+            size.is_power_of_two() && size <= 0x10000,
+            "Mirrored memory size must be a power of two no larger than 64K"
+        );
+        MirroredMemory {
+            data: vec![0xFF; size],
+            mask: size - 1,
+        }
+    }
+}
+
+impl MemoryBus for MirroredMemory {
+    fn write(&mut self, addr: u16, val: u8, _cycles: u64) {
+        self.data[usize::from(addr) & self.mask] = val;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.data[usize::from(addr) & self.mask]
+    }
+}
+
+impl Deref for MirroredMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for MirroredMemory {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+#[test]
+fn mirrored_memory_test() {
+    let mut mem = MirroredMemory::new(0x4000);
+    mem.write(0x2400, 0x42, 0);
+    assert_eq!(mem.peek(0x6400), 0x42);
+    assert_eq!(mem.read(0xE400, 0), 0x42);
+}
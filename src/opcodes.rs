@@ -0,0 +1,303 @@
+//! Size and timing of the 256 opcodes, on the 8080, the 8085 and the Z80.
+
+use crate::state::Model;
+
+/// Static properties of an opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opcode {
+    /// Length of the instruction in bytes, including operands.
+    pub length: u8,
+    /// Cycles taken, or for conditional instructions whose timing depends
+    /// on the condition, cycles taken when it is not met.
+    pub cycles: u8,
+    /// Cycles taken by conditional instructions when the condition is met:
+    /// CALL and RET on the 8080, and also jumps on the 8085. Equal to
+    /// `cycles` for every other opcode.
+    pub cycles_taken: u8,
+}
+
+const fn op(length: u8, cycles: u8) -> Opcode {
+    Opcode {
+        length,
+        cycles,
+        cycles_taken: cycles,
+    }
+}
+
+const fn conditional(length: u8, cycles: u8, cycles_taken: u8) -> Opcode {
+    Opcode {
+        length,
+        cycles,
+        cycles_taken,
+    }
+}
+
+const fn decode(opcode: u8) -> Opcode {
+    match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 => op(3, 10),  // LXI
+        0x22 | 0x2a => op(3, 16),                // SHLD, LHLD
+        0x32 | 0x3a => op(3, 13),                // STA, LDA
+        0x02 | 0x12 | 0x0a | 0x1a => op(1, 7),   // STAX, LDAX
+        _ if opcode & 0xc7 == 0x03 => op(1, 5),  // INX, DCX
+        0x34 | 0x35 => op(1, 10),                // INR M, DCR M
+        _ if opcode & 0xc6 == 0x04 => op(1, 5),  // INR, DCR
+        0x36 => op(2, 10),                       // MVI M
+        _ if opcode & 0xc7 == 0x06 => op(2, 7),  // MVI
+        _ if opcode & 0xcf == 0x09 => op(1, 10), // DAD
+        0x00..=0x3f => op(1, 4),                 // NOP, rotations, DAA, CMA, STC, CMC
+        0x76 => op(1, 7),                        // HLT
+        0x40..=0x7f if opcode & 0x07 == 6 || opcode & 0x38 == 0x30 => op(1, 7), // MOV M
+        0x40..=0x7f => op(1, 5),                 // MOV
+        0x80..=0xbf if opcode & 0x07 == 6 => op(1, 7), // ALU M
+        0x80..=0xbf => op(1, 4),                 // ALU
+        _ if opcode & 0xc7 == 0xc0 => conditional(1, 5, 11), // Rcc
+        0xc9 | 0xd9 => op(1, 10),                // RET
+        _ if opcode & 0xcf == 0xc1 => op(1, 10), // POP
+        _ if opcode & 0xc7 == 0xc2 => op(3, 10), // Jcc
+        0xc3 | 0xcb => op(3, 10),                // JMP
+        0xd3 | 0xdb => op(2, 10),                // OUT, IN
+        0xe3 => op(1, 18),                       // XTHL
+        0xe9 | 0xf9 => op(1, 5),                 // PCHL, SPHL
+        0xeb => op(1, 4),                        // XCHG
+        0xf3 | 0xfb => op(1, 4),                 // DI, EI
+        _ if opcode & 0xc7 == 0xc4 => conditional(3, 11, 17), // Ccc
+        _ if opcode & 0xcf == 0xcd => op(3, 17), // CALL
+        _ if opcode & 0xcf == 0xc5 => op(1, 11), // PUSH
+        _ if opcode & 0xc7 == 0xc6 => op(2, 7),  // Immediate ALU
+        _ => op(1, 11),                          // RST
+    }
+}
+
+/// The 8085 runs most instructions in fewer states, but takes longer for
+/// CALL, PUSH, RST and 16 bit increments. It also decodes the opcodes left
+/// undocumented by Intel as extra instructions.
+const fn decode_8085(opcode: u8) -> Opcode {
+    match opcode {
+        0x08 | 0x18 => op(1, 10),               // DSUB, RDEL
+        0x10 => op(1, 7),                       // ARHL
+        0x20 | 0x30 => op(1, 4),                // RIM, SIM
+        0x28 | 0x38 => op(2, 10),               // LDHI, LDSI
+        _ if opcode & 0xc7 == 0x03 => op(1, 6), // INX, DCX
+        _ if opcode & 0xc6 == 0x04 && opcode & 0x38 != 0x30 => op(1, 4), // INR, DCR
+        0x76 => op(1, 5),                       // HLT
+        0x40..=0x7f if opcode & 0x07 == 6 || opcode & 0x38 == 0x30 => op(1, 7), // MOV M
+        0x40..=0x7f => op(1, 4),                // MOV
+        _ if opcode & 0xc7 == 0xc0 => conditional(1, 6, 12), // Rcc
+        0xcb => conditional(1, 6, 12),          // RSTV
+        0xd9 | 0xed => op(1, 10),               // SHLX, LHLX
+        _ if opcode & 0xc7 == 0xc2 => conditional(3, 7, 10), // Jcc
+        0xdd | 0xfd => conditional(3, 7, 10),   // JNK, JK
+        0xe3 => op(1, 16),                      // XTHL
+        0xe9 | 0xf9 => op(1, 6),                // PCHL, SPHL
+        _ if opcode & 0xc7 == 0xc4 => conditional(3, 9, 18), // Ccc
+        0xcd => op(3, 18),                      // CALL
+        _ if opcode & 0xcf == 0xc5 => op(1, 12), // PUSH
+        _ if opcode & 0xc7 == 0xc7 => op(1, 12), // RST
+        _ => decode(opcode),
+    }
+}
+
+/// Timings of the unprefixed Z80 instructions. Prefixed ones are timed as
+/// they are decoded.
+const fn decode_z80(opcode: u8) -> Opcode {
+    match opcode {
+        0x08 | 0xd9 => op(1, 4),                            // EX AF,AF', EXX
+        0x10 => conditional(2, 8, 13),                      // DJNZ
+        0x18 => op(2, 12),                                  // JR
+        0x20 | 0x28 | 0x30 | 0x38 => conditional(2, 7, 12), // JR cc
+        0x34 | 0x35 => op(1, 11),                           // INC (HL), DEC (HL)
+        _ if opcode & 0xc7 == 0x03 => op(1, 6),             // INC rr, DEC rr
+        _ if opcode & 0xc6 == 0x04 => op(1, 4),             // INC r, DEC r
+        _ if opcode & 0xcf == 0x09 => op(1, 11),            // ADD HL,rr
+        0x76 => op(1, 4),                                   // HALT
+        0x40..=0x7f if opcode & 0x07 == 6 || opcode & 0x38 == 0x30 => op(1, 7), // LD (HL)
+        0x40..=0x7f => op(1, 4),                            // LD r,r'
+        0xcb | 0xdd | 0xed | 0xfd => op(1, 4),              // Prefixes
+        0xd3 | 0xdb => op(2, 11),                           // OUT (n),A, IN A,(n)
+        0xe3 => op(1, 19),                                  // EX (SP),HL
+        0xe9 => op(1, 4),                                   // JP (HL)
+        0xf9 => op(1, 6),                                   // LD SP,HL
+        _ if opcode & 0xc7 == 0xc4 => conditional(3, 10, 17), // CALL cc
+        _ => decode(opcode),
+    }
+}
+
+pub const OPCODES: [Opcode; 256] = {
+    let mut table = [op(1, 4); 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = decode(i as u8);
+        i += 1;
+    }
+    table
+};
+
+pub const OPCODES_8085: [Opcode; 256] = {
+    let mut table = [op(1, 4); 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = decode_8085(i as u8);
+        i += 1;
+    }
+    table
+};
+
+pub const OPCODES_Z80: [Opcode; 256] = {
+    let mut table = [op(1, 4); 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = decode_z80(i as u8);
+        i += 1;
+    }
+    table
+};
+
+/// The opcode table of `model`.
+pub fn opcodes(model: Model) -> &'static [Opcode; 256] {
+    match model {
+        Model::I8080 => &OPCODES,
+        Model::I8085 => &OPCODES_8085,
+        Model::Z80 => &OPCODES_Z80,
+    }
+}
+
+/// States per instruction, from the opcode map of the Intel 8080
+/// Microcomputer Systems User's Manual. Conditional CALL and RET list the
+/// states when the condition is not met.
+#[cfg(test)]
+#[rustfmt::skip]
+const DATASHEET_CYCLES: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 0x
+     4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4, // 1x
+     4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4, // 2x
+     4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4, // 3x
+     5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 4x
+     5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 5x
+     5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5, // 6x
+     7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5, // 7x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+     5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Cx
+     5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11, // Dx
+     5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Ex
+     5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11, // Fx
+];
+
+#[test]
+fn datasheet_timings() {
+    for (i, opcode) in OPCODES.iter().enumerate() {
+        assert_eq!(
+            opcode.cycles, DATASHEET_CYCLES[i],
+            "Wrong cycles for opcode {:02X}",
+            i
+        );
+        let taken = match i & 0xc7 {
+            0xc0 => 11, // Rcc
+            0xc4 => 17, // Ccc
+            _ => DATASHEET_CYCLES[i],
+        };
+        assert_eq!(
+            opcode.cycles_taken, taken,
+            "Wrong cycles for opcode {:02X} when taken",
+            i
+        );
+    }
+}
+
+/// States per instruction, from the instruction set summary of the Intel
+/// 8085AH datasheet, completed with the undocumented instructions.
+/// Conditional instructions list the states when the condition is not met.
+#[cfg(test)]
+#[rustfmt::skip]
+const DATASHEET_CYCLES_8085: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 10,  7,  6,  4,  4,  7,  4, 10, 10,  7,  6,  4,  4,  7,  4, // 0x
+     7, 10,  7,  6,  4,  4,  7,  4, 10, 10,  7,  6,  4,  4,  7,  4, // 1x
+     4, 10, 16,  6,  4,  4,  7,  4, 10, 10, 16,  6,  4,  4,  7,  4, // 2x
+     4, 10, 13,  6, 10, 10, 10,  4, 10, 10, 13,  6,  4,  4,  7,  4, // 3x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 4x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 5x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 6x
+     7,  7,  7,  7,  7,  7,  5,  7,  4,  4,  4,  4,  4,  4,  7,  4, // 7x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+     6, 10,  7, 10,  9, 12,  7, 12,  6, 10,  7,  6,  9, 18,  7, 12, // Cx
+     6, 10,  7, 10,  9, 12,  7, 12,  6, 10,  7, 10,  9,  7,  7, 12, // Dx
+     6, 10,  7, 16,  9, 12,  7, 12,  6,  6,  7,  4,  9, 10,  7, 12, // Ex
+     6, 10,  7,  4,  9, 12,  7, 12,  6,  6,  7,  4,  9,  7,  7, 12, // Fx
+];
+
+#[test]
+fn datasheet_timings_8085() {
+    for (i, opcode) in OPCODES_8085.iter().enumerate() {
+        assert_eq!(
+            opcode.cycles, DATASHEET_CYCLES_8085[i],
+            "Wrong cycles for opcode {:02X}",
+            i
+        );
+        let taken = match i {
+            _ if i & 0xc7 == 0xc0 || i == 0xcb => 12, // Rcc, RSTV
+            _ if i & 0xc7 == 0xc2 || i == 0xdd || i == 0xfd => 10, // Jcc, JNK, JK
+            _ if i & 0xc7 == 0xc4 => 18,              // Ccc
+            _ => DATASHEET_CYCLES_8085[i],
+        };
+        assert_eq!(
+            opcode.cycles_taken, taken,
+            "Wrong cycles for opcode {:02X} when taken",
+            i
+        );
+    }
+}
+
+/// T-states of the unprefixed instructions, from the Zilog Z80 CPU User
+/// Manual. Conditional instructions list the states when the condition is
+/// not met, and prefixes the states of their own fetch.
+#[cfg(test)]
+#[rustfmt::skip]
+const DATASHEET_CYCLES_Z80: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 10,  7,  6,  4,  4,  7,  4,  4, 11,  7,  6,  4,  4,  7,  4, // 0x
+     8, 10,  7,  6,  4,  4,  7,  4, 12, 11,  7,  6,  4,  4,  7,  4, // 1x
+     7, 10, 16,  6,  4,  4,  7,  4,  7, 11, 16,  6,  4,  4,  7,  4, // 2x
+     7, 10, 13,  6, 11, 11, 10,  4,  7, 11, 13,  6,  4,  4,  7,  4, // 3x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 4x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 5x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 6x
+     7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4, // 7x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 8x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 9x
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Ax
+     4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // Bx
+     5, 10, 10, 10, 10, 11,  7, 11,  5, 10, 10,  4, 10, 17,  7, 11, // Cx
+     5, 10, 10, 11, 10, 11,  7, 11,  5,  4, 10, 11, 10,  4,  7, 11, // Dx
+     5, 10, 10, 19, 10, 11,  7, 11,  5,  4, 10,  4, 10,  4,  7, 11, // Ex
+     5, 10, 10,  4, 10, 11,  7, 11,  5,  6, 10,  4, 10,  4,  7, 11, // Fx
+];
+
+#[test]
+fn datasheet_timings_z80() {
+    for (i, opcode) in OPCODES_Z80.iter().enumerate() {
+        assert_eq!(
+            opcode.cycles, DATASHEET_CYCLES_Z80[i],
+            "Wrong cycles for opcode {:02X}",
+            i
+        );
+        let taken = match i {
+            0x10 => 13,                      // DJNZ
+            0x20 | 0x28 | 0x30 | 0x38 => 12, // JR cc
+            _ if i & 0xc7 == 0xc0 => 11,     // RET cc
+            _ if i & 0xc7 == 0xc4 => 17,     // CALL cc
+            _ => DATASHEET_CYCLES_Z80[i],
+        };
+        assert_eq!(
+            opcode.cycles_taken, taken,
+            "Wrong cycles for opcode {:02X} when taken",
+            i
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Device events timed against the CPU's cycle counter.
+
+/// A callback fired once the CPU's cycle counter reaches the cycle it was
+/// scheduled at, which it receives. It returns the cycle to fire it again
+/// at, if any.
+pub type Callback<C> = Box<dyn FnMut(&mut C, u64) -> Option<u64> + Send>;
+
+struct Event<C> {
+    at: u64,
+    callback: Callback<C>,
+}
+
+/// Callbacks ordered by the cycle they are due at. Callbacks due at the same
+/// cycle fire in the order they were scheduled.
+pub struct Scheduler<C> {
+    /// Latest event first, so the next one due is popped from the end.
+    events: Vec<Event<C>>,
+}
+
+impl<C> Default for Scheduler<C> {
+    fn default() -> Self {
+        Scheduler { events: Vec::new() }
+    }
+}
+
+impl<C> Scheduler<C> {
+    pub fn schedule<F>(&mut self, at: u64, callback: F)
+    where
+        F: FnMut(&mut C, u64) -> Option<u64> + Send + 'static,
+    {
+        self.reschedule(at, Box::new(callback));
+    }
+
+    /// Schedules a callback returned by `pop_due` again.
+    pub fn reschedule(&mut self, at: u64, callback: Callback<C>) {
+        let pos = self.events.iter().position(|e| e.at <= at);
+        let pos = pos.unwrap_or(self.events.len());
+        self.events.insert(pos, Event { at, callback });
+    }
+
+    /// The cycle the next event is due at.
+    pub fn next_due(&self) -> Option<u64> {
+        self.events.last().map(|e| e.at)
+    }
+
+    /// Removes the next event if it is due at cycle `now`, returning the
+    /// cycle it was scheduled at and its callback.
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, Callback<C>)> {
+        match self.events.last() {
+            Some(event) if event.at <= now => {
+                let Event { at, callback } = self.events.pop()?;
+                Some((at, callback))
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes every pending event.
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
@@ -1,4 +1,12 @@
 use std::default::Default;
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::StepError;
+use crate::i8085::Interrupts8085;
+use crate::interrupt::BusInstruction;
+use crate::memory::{FlatMemory, MemoryBus};
+use crate::z80::Z80Registers;
 
 #[derive(Default)]
 pub struct Flags {
@@ -7,15 +15,79 @@ pub struct Flags {
     pub p: bool,
     pub cy: bool,
     pub ac: bool, //pad: i32
+    /// Signed overflow of the last addition or subtraction. Only visible on
+    /// the 8085, which keeps it in bit 1 of the PSW.
+    pub v: bool,
+    /// Undocumented 8085 flag in bit 5 of the PSW: S xor V after additions
+    /// and subtractions, or the wraparound of INX and DCX.
+    pub k: bool,
+    /// Z80 flag set by subtractions, for DAA.
+    pub n: bool,
 }
 
 impl Flags {
+    /// Bits of the PSW that always read as 1.
+    const PSW_ONES: u8 = 0b0000_0010;
+
+    /// Packs the flags in the format pushed by PUSH PSW: S Z 0 AC 0 P 1 CY.
+    pub fn to_psw(&self) -> u8 {
+        (u8::from(self.s) << 7)
+            | (u8::from(self.z) << 6)
+            | (u8::from(self.ac) << 4)
+            | (u8::from(self.p) << 2)
+            | u8::from(self.cy)
+            | Self::PSW_ONES
+    }
+
+    /// Unpacks flags popped by POP PSW. Unused bits are ignored.
+    pub fn from_psw(psw: u8) -> Self {
+        Flags {
+            s: psw & 0x80 != 0,
+            z: psw & 0x40 != 0,
+            ac: psw & 0x10 != 0,
+            p: psw & 0x04 != 0,
+            cy: psw & 0x01 != 0,
+            v: false,
+            k: false,
+            n: false,
+        }
+    }
+
+    /// Packs the flags as the 8085 pushes them: S Z K AC 0 P V CY.
+    pub fn to_psw_8085(&self) -> u8 {
+        let psw = self.to_psw() & !Self::PSW_ONES;
+        psw | (u8::from(self.k) << 5) | (u8::from(self.v) << 1)
+    }
+
+    pub fn from_psw_8085(psw: u8) -> Self {
+        Flags {
+            v: psw & 0x02 != 0,
+            k: psw & 0x20 != 0,
+            ..Flags::from_psw(psw)
+        }
+    }
+
+    /// Packs the flags as the Z80 pushes them: S Z 0 H 0 P/V N C.
+    pub fn to_psw_z80(&self) -> u8 {
+        (self.to_psw() & !Self::PSW_ONES) | (u8::from(self.n) << 1)
+    }
+
+    pub fn from_psw_z80(psw: u8) -> Self {
+        Flags {
+            n: psw & 0x02 != 0,
+            ..Flags::from_psw(psw)
+        }
+    }
+
     pub fn clear(&mut self) {
         self.z = true;
         self.s = false;
         self.p = true;
         self.cy = false;
         self.ac = false;
+        self.v = false;
+        self.k = false;
+        self.n = false;
     }
 }
 
@@ -35,7 +107,68 @@ fn parity_test() {
     assert!(parity(0x99));
 }
 
-pub struct State8080 {
+#[test]
+fn psw_round_trip() {
+    for psw in 0..=255u8 {
+        let packed = Flags::from_psw(psw).to_psw();
+        assert_eq!(packed, (psw & 0b1101_0101) | 0b0000_0010);
+        assert_eq!(Flags::from_psw(packed).to_psw(), packed);
+        let packed = Flags::from_psw_8085(psw).to_psw_8085();
+        assert_eq!(packed, psw & 0b1111_0111);
+        let packed = Flags::from_psw_z80(psw).to_psw_z80();
+        assert_eq!(packed, psw & 0b1101_0111);
+    }
+}
+
+/// The CPU being emulated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    I8080,
+    /// Adds RIM, SIM, the TRAP and RST 5.5 to 7.5 interrupt inputs and the
+    /// serial lines, with its own timings and undocumented instructions.
+    I8085,
+    /// Runs the Z80 instruction set, with its own flags and timings.
+    Z80,
+}
+
+/// What happens when the CPU writes to a region declared as ROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomPolicy {
+    /// The write is silently dropped.
+    Ignore,
+    /// The write is dropped and reported on stderr.
+    Log,
+    /// The write is dropped and reported as an error by `try_step`.
+    /// `step` panics.
+    Error,
+}
+
+struct RomRegion {
+    range: Range<usize>,
+    policy: RomPolicy,
+}
+
+/// A CPU write to a ROM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RomWrite {
+    pub addr: usize,
+    pub val: u8,
+    /// Address of the instruction that performed the write.
+    pub pc: usize,
+}
+
+impl fmt::Display for RomWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Write of {:02X} to ROM at {:04X} by instruction at {:04X}",
+            self.val, self.addr, self.pc
+        )
+    }
+}
+
+pub struct State8080<M: MemoryBus = FlatMemory> {
     pub a: u8,
     pub b: u8,
     pub c: u8,
@@ -45,13 +178,42 @@ pub struct State8080 {
     pub l: u8,
     pub sp: usize,
     pub pc: usize,
-    pub memory: Vec<u8>,
+    /// Chosen at construction, with `Emu8080::with_model`.
+    pub(crate) model: Model,
+    pub memory: M,
     pub fl: Flags,
     pub int_enable: bool,
+    /// Set by EI: interrupts are not accepted until after the next
+    /// instruction.
+    pub int_delay: bool,
+    /// Interrupt requested with `Emu8080::interrupt`, not yet accepted.
+    pub int_request: Option<BusInstruction>,
+    /// Set by HLT, cleared when an interrupt is accepted.
+    pub halted: bool,
+    /// Interrupt inputs of the 8085, ignored on the 8080.
+    pub i8085: Interrupts8085,
+    /// Registers of the Z80, ignored on the 8080.
+    pub z80: Z80Registers,
+    /// Cycles elapsed since the CPU was created, including idle cycles
+    /// spent halted. Updated at the end of each instruction.
+    pub cycles: u64,
+    /// Address of the instruction being executed.
+    pub(crate) instr_pc: usize,
+    /// Instruction being fetched from the data bus during an interrupt
+    /// acknowledge cycle, and the position of the next byte to fetch.
+    pub(crate) bus: Option<(BusInstruction, usize)>,
+    rom: Vec<RomRegion>,
+    fault: Option<StepError>,
 }
 
-impl Default for State8080 {
+impl<M: MemoryBus + Default> Default for State8080<M> {
     fn default() -> Self {
+        State8080::new(Default::default())
+    }
+}
+
+impl<M: MemoryBus> State8080<M> {
+    pub fn new(memory: M) -> Self {
         State8080 {
             a: 0,
             b: 0,
@@ -62,16 +224,73 @@ impl Default for State8080 {
             l: 0,
             sp: 0,
             pc: 0,
-            memory: vec![0xFF; 0x10000],
+            model: Model::I8080,
+            memory,
             fl: Default::default(),
             int_enable: false,
+            int_delay: false,
+            int_request: None,
+            halted: false,
+            i8085: Interrupts8085::default(),
+            z80: Z80Registers::default(),
+            cycles: 0,
+            instr_pc: 0,
+            bus: None,
+            rom: Vec::new(),
+            fault: None,
         }
     }
-}
 
-impl State8080 {
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Declares `range` as ROM: CPU writes to it are dropped and handled
+    /// according to `policy`.
+    pub fn protect(&mut self, range: Range<usize>, policy: RomPolicy) {
+        self.rom.push(RomRegion { range, policy });
+    }
+
+    /// Records an error raised while executing the current instruction.
+    /// Only the first one is kept.
+    pub(crate) fn fault(&mut self, err: StepError) {
+        self.fault.get_or_insert(err);
+    }
+
+    pub(crate) fn take_fault(&mut self) -> Option<StepError> {
+        self.fault.take()
+    }
+
+    /// Whether the current instruction was refused, and takes no cycles.
+    pub(crate) fn is_rejected(&self) -> bool {
+        matches!(self.fault, Some(StepError::IllegalOpcode { .. }))
+    }
+
+    pub fn read_byte(&mut self, addr: usize) -> u8 {
+        self.memory.read(addr as u16, self.cycles)
+    }
+
+    pub fn write_byte(&mut self, addr: usize, val: u8) {
+        let addr = addr & 0xFFFF;
+        if let Some(region) = self.rom.iter().find(|r| r.range.contains(&addr)) {
+            let violation = RomWrite {
+                addr,
+                val,
+                pc: self.instr_pc,
+            };
+            match region.policy {
+                RomPolicy::Ignore => {}
+                RomPolicy::Log => eprintln!("{}", violation),
+                RomPolicy::Error => self.fault(StepError::RomWrite(violation)),
+            }
+            return;
+        }
+        self.memory.write(addr as u16, val, self.cycles)
+    }
+
+    /// Only the low three bits of `reg` are used, as in opcode encodings.
     pub fn set_register(&mut self, reg: u8, val: u8) {
-        match reg {
+        match reg & 7 {
             0 => self.b = val,
             1 => self.c = val,
             2 => self.d = val,
@@ -80,15 +299,16 @@ impl State8080 {
             5 => self.l = val,
             6 => {
                 let addr = self.hl();
-                self.memory[addr] = val
+                self.write_byte(addr, val)
             }
             7 => self.a = val,
-            _ => panic!("Invalid register number"),
+            _ => unreachable!(),
         }
     }
 
+    /// Only the low three bits of `reg` are used, as in opcode encodings.
     pub fn get_register(&mut self, reg: u8) -> u8 {
-        match reg {
+        match reg & 7 {
             0 => self.b,
             1 => self.c,
             2 => self.d,
@@ -97,7 +317,7 @@ impl State8080 {
             5 => self.l,
             6 => self.at_hl(),
             7 => self.a,
-            _ => panic!("Invalid register number"),
+            _ => unreachable!(),
         }
     }
 
@@ -157,35 +377,55 @@ impl State8080 {
         (usize::from(self.h) << 8) | usize::from(self.l)
     }
 
-    pub fn at_bc(&self) -> u8 {
+    pub fn at_bc(&mut self) -> u8 {
         let addr = self.bc();
-        self.memory[addr]
+        self.read_byte(addr)
     }
 
-    pub fn at_de(&self) -> u8 {
+    pub fn at_de(&mut self) -> u8 {
         let addr = self.de();
-        self.memory[addr]
+        self.read_byte(addr)
     }
 
-    pub fn at_hl(&self) -> u8 {
+    pub fn at_hl(&mut self) -> u8 {
         let addr = self.hl();
-        self.memory[addr]
+        self.read_byte(addr)
     }
 
-    pub fn byte1(&self) -> u8 {
-        self.memory[self.pc]
+    /// Reads the next instruction byte and advances the program counter, or
+    /// takes it from the data bus during an interrupt acknowledge cycle.
+    pub fn fetch(&mut self) -> u8 {
+        if let Some((instr, ref mut pos)) = self.bus {
+            *pos += 1;
+            return instr.byte(*pos - 1);
+        }
+        let pc = self.pc;
+        self.pc = (pc + 1) & 0xFFFF;
+        self.read_byte(pc)
     }
 
-    pub fn byte2(&self) -> u8 {
-        self.memory[self.pc + 1]
+    pub fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch();
+        let high = self.fetch();
+        (u16::from(high) << 8) | u16::from(low)
     }
 
-    pub fn word(&self) -> u16 {
+    pub fn byte1(&mut self) -> u8 {
+        self.read_byte(self.pc)
+    }
+
+    pub fn byte2(&mut self) -> u8 {
+        self.read_byte(self.pc + 1)
+    }
+
+    pub fn word(&mut self) -> u16 {
         self.word_at(self.pc)
     }
 
-    pub fn word_at(&self, addr: usize) -> u16 {
-        (u16::from(self.memory[addr + 1]) << 8) | u16::from(self.memory[addr])
+    pub fn word_at(&mut self, addr: usize) -> u16 {
+        let low = self.read_byte(addr);
+        let high = self.read_byte(addr + 1);
+        (u16::from(high) << 8) | u16::from(low)
     }
 
     pub fn set_r(&mut self, res: u8) {
@@ -199,3 +439,33 @@ impl State8080 {
         self.fl.cy = res > 0xff;
     }
 }
+
+#[test]
+fn word_access_order() {
+    #[derive(Default)]
+    struct LoggingBus {
+        memory: FlatMemory,
+        reads: Vec<u16>,
+    }
+
+    impl MemoryBus for LoggingBus {
+        fn read(&mut self, addr: u16, _cycles: u64) -> u8 {
+            self.reads.push(addr);
+            self.memory.peek(addr)
+        }
+
+        fn write(&mut self, addr: u16, val: u8, cycles: u64) {
+            self.memory.write(addr, val, cycles)
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.memory.peek(addr)
+        }
+    }
+
+    let mut state = State8080::new(LoggingBus::default());
+    state.memory.write(0x1234, 0x78, 0);
+    state.memory.write(0x1235, 0x56, 0);
+    assert_eq!(state.word_at(0x1234), 0x5678);
+    assert_eq!(state.memory.reads, [0x1234, 0x1235]);
+}
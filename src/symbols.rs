@@ -0,0 +1,151 @@
+//! Names and comments for addresses, shown by the disassembler and in
+//! traces.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::SymbolError;
+
+/// A symbol table, usually loaded from a file with `parse`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    comments: BTreeMap<u16, String>,
+}
+
+/// Parses `text` as a number. It is hexadecimal with a `$` or `0x` prefix
+/// or an `H` suffix, and in `radix` otherwise.
+fn parse_number(text: &str, radix: u32) -> Option<u16> {
+    let (digits, radix) = if let Some(digits) = text.strip_prefix('$') {
+        (digits, 16)
+    } else if let Some(digits) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (digits, 16)
+    } else if let Some(digits) = text.strip_suffix('H').or_else(|| text.strip_suffix('h')) {
+        (digits, 16)
+    } else {
+        (text, radix)
+    };
+    u16::from_str_radix(digits, radix).ok()
+}
+
+impl Symbols {
+    pub const fn new() -> Self {
+        Symbols {
+            names: BTreeMap::new(),
+            comments: BTreeMap::new(),
+        }
+    }
+
+    /// Parses a symbol file. Each line holds either:
+    ///
+    /// - one or more `ADDR NAME` pairs, with `ADDR` in hexadecimal, as in
+    ///   the .SYM files of CP/M assemblers;
+    /// - `NAME EQU VALUE` or `NAME = VALUE`, with `VALUE` in decimal unless
+    ///   marked hexadecimal.
+    ///
+    /// Text after `;` is a comment on the first address of the line, which
+    /// may also be given alone to only comment it.
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let (entries, comment) = match line.find(';') {
+                Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+                None => (line, ""),
+            };
+            let error = || SymbolError {
+                line: i + 1,
+                text: line.to_string(),
+            };
+            let tokens: Vec<_> = entries.split_whitespace().collect();
+            let first = match tokens[..] {
+                [] => continue,
+                [name, equ, value] if equ.eq_ignore_ascii_case("EQU") || equ == "=" => {
+                    let addr = parse_number(value, 10).ok_or_else(error)?;
+                    symbols.insert(addr, name.trim_end_matches(':'));
+                    addr
+                }
+                [addr] => parse_number(addr, 16).ok_or_else(error)?,
+                _ if tokens.len() % 2 == 0 => {
+                    for pair in tokens.chunks(2) {
+                        let addr = parse_number(pair[0], 16).ok_or_else(error)?;
+                        symbols.insert(addr, pair[1].trim_end_matches(':'));
+                    }
+                    parse_number(tokens[0], 16).ok_or_else(error)?
+                }
+                _ => return Err(error()),
+            };
+            if !comment.is_empty() {
+                symbols.set_comment(first, comment);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Reads and parses the symbol file at `path`. Parse errors are
+    /// reported as `InvalidData`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Symbols::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Names `addr`, replacing any previous name.
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    pub fn set_comment(&mut self, addr: u16, comment: &str) {
+        self.comments.insert(addr, comment.to_string());
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    pub fn comment(&self, addr: u16) -> Option<&str> {
+        self.comments.get(&addr).map(String::as_str)
+    }
+
+    /// Named addresses, in increasing order.
+    pub fn names(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.comments.is_empty()
+    }
+}
+
+#[test]
+fn parse_formats() {
+    let symbols = Symbols::parse(
+        "; Space Invaders
+0000 Reset
+15D3 DrawSprite ; Draws the sprite at HL
+0100 START\t0103 LOOP:
+ScoreP1 EQU 20F8H
+Lives: equ $21FF
+Count = 16
+0008 ; Mid screen interrupt
+",
+    )
+    .unwrap();
+    assert_eq!(symbols.name(0x15d3), Some("DrawSprite"));
+    assert_eq!(symbols.comment(0x15d3), Some("Draws the sprite at HL"));
+    assert_eq!(symbols.name(0x0103), Some("LOOP"));
+    assert_eq!(symbols.name(0x20f8), Some("ScoreP1"));
+    assert_eq!(symbols.name(0x21ff), Some("Lives"));
+    assert_eq!(symbols.name(0x10), Some("Count"));
+    assert_eq!(symbols.name(0x0008), None);
+    assert_eq!(symbols.comment(0x0008), Some("Mid screen interrupt"));
+    assert_eq!(symbols.names().count(), 7);
+}
+
+#[test]
+fn parse_errors() {
+    let err = Symbols::parse("0000 Reset\nDrawSprite 15D3 extra\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    assert!(Symbols::parse("XYZW Name").is_err());
+    assert!(Symbols::parse("Name EQU 10000H").is_err());
+}
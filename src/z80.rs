@@ -0,0 +1,793 @@
+//! The Zilog Z80, built on the 8080 core: the index registers, the
+//! alternate register set, I and R, the interrupt modes and the CB, DD, ED
+//! and FD prefixed instructions.
+//!
+//! Bits 3 and 5 of F, left undocumented by Zilog, are not emulated and read
+//! as 0.
+
+use crate::interrupt::{BusInstruction, InterruptSource};
+use crate::memory::MemoryBus;
+use crate::opcodes::OPCODES_Z80;
+use crate::state::Flags;
+use crate::{Emu8080, InOutHandler, Instruction};
+
+/// Address called when a non-maskable interrupt is accepted.
+pub const NMI_VECTOR: u16 = 0x66;
+
+/// Address called by interrupts in mode 1.
+const IM1_VECTOR: u16 = 0x38;
+
+/// Registers the Z80 adds to the 8080.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Z80Registers {
+    pub ix: u16,
+    pub iy: u16,
+    /// The alternate register set, exchanged with the main one by EX AF,AF'
+    /// and EXX. F is packed as by PUSH AF.
+    pub af_alt: u16,
+    pub bc_alt: u16,
+    pub de_alt: u16,
+    pub hl_alt: u16,
+    /// High byte of the interrupt vector table in mode 2.
+    pub i: u8,
+    /// Memory refresh counter, incremented by each opcode fetch. Bit 7 only
+    /// changes with LD R,A.
+    pub r: u8,
+    /// Interrupt mode set by IM: 0, 1 or 2.
+    pub im: u8,
+    /// Copy of the interrupt enable flag kept while an NMI is serviced, and
+    /// restored by RETN.
+    pub iff2: bool,
+    /// Set by `nmi` until the interrupt is accepted.
+    nmi: bool,
+}
+
+impl Z80Registers {
+    /// Pulses the NMI input. The interrupt is accepted at the next
+    /// instruction boundary whatever the interrupt enable flag.
+    pub fn nmi(&mut self) {
+        self.nmi = true;
+    }
+
+    fn refresh(&mut self) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7f);
+    }
+}
+
+/// The index register replacing HL after a DD or FD prefix.
+#[derive(Clone, Copy)]
+enum Index {
+    IX,
+    IY,
+}
+
+/// Whether `opcode` accesses memory at HL, which becomes IX or IY plus a
+/// displacement after a DD or FD prefix. H and L are not replaced in those
+/// instructions.
+pub(crate) fn uses_memory_at_hl(opcode: u8) -> bool {
+    match opcode {
+        0x34..=0x36 => true,
+        0x76 => false,
+        0x40..=0x7f => opcode & 0x07 == 6 || opcode & 0x38 == 0x30,
+        0x80..=0xbf => opcode & 0x07 == 6,
+        _ => false,
+    }
+}
+
+impl<T: InOutHandler, M: MemoryBus, I: InterruptSource> Emu8080<T, M, I> {
+    /// Fetches an opcode following a prefix, which counts as an opcode
+    /// fetch for the R register.
+    fn fetch_opcode(&mut self) -> u8 {
+        self.state.z80.refresh();
+        self.fetch()
+    }
+
+    fn set_pair(&mut self, op: u8, val: usize) {
+        self.set_long(op, (val as u8, (val >> 8) as u8));
+    }
+
+    fn index(&self, index: Index) -> u16 {
+        match index {
+            Index::IX => self.z80.ix,
+            Index::IY => self.z80.iy,
+        }
+    }
+
+    fn set_index(&mut self, index: Index, val: u16) {
+        match index {
+            Index::IX => self.state.z80.ix = val,
+            Index::IY => self.state.z80.iy = val,
+        }
+    }
+
+    /// Exchanges HL with `index`, so that 8080 handlers operate on it.
+    fn swap_index(&mut self, index: Index) {
+        let hl = self.hl() as u16;
+        let val = self.index(index);
+        self.h = (val >> 8) as u8;
+        self.l = val as u8;
+        self.set_index(index, hl);
+    }
+
+    /// Whether the condition of the conditional `opcode` holds.
+    fn z80_condition(&self, opcode: u8) -> bool {
+        match opcode {
+            0x10 => self.b != 1, // DJNZ, before decrementing B
+            // JR cc, mapped to the same conditions of JP cc
+            0x20 | 0x28 | 0x30 | 0x38 => self.get_flag(0xc2 | (opcode & 0x18)),
+            _ => self.get_flag(opcode),
+        }
+    }
+
+    /// Executes the instruction starting with `opcode`, including any
+    /// prefix, and returns the cycles it took.
+    pub(crate) fn dispatch_z80(&mut self, opcode: u8) -> usize {
+        self.state.z80.refresh();
+        let cycles = match opcode {
+            0xcb => self.cb_prefix(),
+            0xdd => self.index_prefix(Index::IX),
+            0xed => self.ed_prefix(),
+            0xfd => self.index_prefix(Index::IY),
+            _ => self.unprefixed(opcode),
+        };
+        self.cycles += u64::from(cycles);
+        usize::from(cycles)
+    }
+
+    fn unprefixed(&mut self, opcode: u8) -> u8 {
+        let info = &OPCODES_Z80[usize::from(opcode)];
+        // Conditions are evaluated before the instruction changes any flag
+        let cycles = if info.cycles_taken != info.cycles && self.z80_condition(opcode) {
+            info.cycles_taken
+        } else {
+            info.cycles
+        };
+        Self::HANDLERS_Z80[usize::from(opcode)](self, opcode);
+        cycles
+    }
+
+    /// Accepts a maskable interrupt in the current interrupt mode, returning
+    /// the cycles taken.
+    pub(crate) fn acknowledge_z80(&mut self, instr: BusInstruction) -> usize {
+        self.int_enable = false;
+        self.state.z80.iff2 = false;
+        self.halted = false;
+        self.instr_pc = self.pc;
+        let cycles = match self.z80.im {
+            0 => {
+                // As on the 8080, with two extra wait states
+                self.bus = Some((instr, 0));
+                let opcode = self.fetch();
+                let cycles = self.dispatch_z80(opcode) + 2;
+                self.bus = None;
+                self.cycles += 2;
+                return cycles;
+            }
+            1 => {
+                self.call(IM1_VECTOR);
+                13
+            }
+            _ => {
+                let entry = (usize::from(self.z80.i) << 8) | usize::from(instr.byte(0));
+                let addr = self.word_at(entry);
+                self.call(addr);
+                19
+            }
+        };
+        self.state.z80.refresh();
+        self.cycles += cycles;
+        cycles as usize
+    }
+
+    /// Accepts a pending NMI, returning the cycles taken.
+    pub(crate) fn sample_nmi(&mut self) -> Option<usize> {
+        if !self.z80.nmi {
+            return None;
+        }
+        self.state.z80.nmi = false;
+        self.state.z80.iff2 = self.int_enable;
+        self.state.z80.refresh();
+        self.int_enable = false;
+        self.int_delay = false;
+        self.halted = false;
+        self.instr_pc = self.pc;
+        self.call(NMI_VECTOR);
+        self.cycles += 11;
+        Some(11)
+    }
+
+    /// Adds `rhs` and `carry` to `lhs`, setting all flags.
+    fn add8(&mut self, lhs: u8, rhs: u8, carry: bool) -> u8 {
+        let carry = u8::from(carry);
+        let ans = u16::from(lhs) + u16::from(rhs) + u16::from(carry);
+        let res = ans as u8;
+        self.fl.s = res & 0x80 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = (lhs & 0xf) + (rhs & 0xf) + carry > 0xf;
+        self.fl.p = (lhs ^ res) & (rhs ^ res) & 0x80 != 0;
+        self.fl.n = false;
+        self.fl.cy = ans > 0xff;
+        res
+    }
+
+    /// Subtracts `rhs` and `borrow` from `lhs`, setting all flags. Unlike on
+    /// the 8080, H is set on a borrow from bit 4.
+    fn sub8(&mut self, lhs: u8, rhs: u8, borrow: bool) -> u8 {
+        let borrow = u8::from(borrow);
+        let res = lhs.wrapping_sub(rhs).wrapping_sub(borrow);
+        self.fl.s = res & 0x80 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = lhs & 0xf < (rhs & 0xf) + borrow;
+        self.fl.p = (lhs ^ rhs) & (lhs ^ res) & 0x80 != 0;
+        self.fl.n = true;
+        self.fl.cy = u16::from(lhs) < u16::from(rhs) + u16::from(borrow);
+        res
+    }
+
+    fn logic(&mut self, res: u8, half_carry: bool) {
+        self.a = res;
+        self.set_r(res);
+        self.fl.ac = half_carry;
+        self.fl.n = false;
+        self.fl.cy = false;
+    }
+
+    fn alu8(&mut self, op: u8, val: u8) {
+        let (a, cy) = (self.a, self.fl.cy);
+        match (op >> 3) & 7 {
+            0 => self.a = self.add8(a, val, false),
+            1 => self.a = self.add8(a, val, cy),
+            2 => self.a = self.sub8(a, val, false),
+            3 => self.a = self.sub8(a, val, cy),
+            4 => self.logic(a & val, true),
+            5 => self.logic(a ^ val, false),
+            6 => self.logic(a | val, false),
+            _ => {
+                self.sub8(a, val, false);
+            }
+        }
+    }
+
+    fn alu_z80(&mut self, op: u8) {
+        let val = self.get_register(op & 0b111);
+        self.alu8(op, val);
+    }
+
+    fn immediate_z80(&mut self, op: u8) {
+        let val = self.fetch();
+        self.alu8(op, val);
+    }
+
+    fn inc8(&mut self, val: u8) -> u8 {
+        let res = val.wrapping_add(1);
+        self.fl.s = res & 0x80 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = val & 0xf == 0xf;
+        self.fl.p = val == 0x7f;
+        self.fl.n = false;
+        res
+    }
+
+    fn dec8(&mut self, val: u8) -> u8 {
+        let res = val.wrapping_sub(1);
+        self.fl.s = res & 0x80 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = val & 0xf == 0;
+        self.fl.p = val == 0x80;
+        self.fl.n = true;
+        res
+    }
+
+    /// INC r and DEC r, which set P/V on overflow.
+    fn inc_dec(&mut self, op: u8) {
+        let reg = (op >> 3) & 7;
+        let val = self.get_register(reg);
+        let res = if op & 1 == 0 {
+            self.inc8(val)
+        } else {
+            self.dec8(val)
+        };
+        self.set_register(reg, res);
+    }
+
+    /// ADD HL,rr, which also sets H from bit 11.
+    fn add_hl(&mut self, op: u8) {
+        let (hl, rr) = (self.hl(), self.get_long(op));
+        let ans = hl + rr;
+        self.fl.ac = (hl & 0xfff) + (rr & 0xfff) > 0xfff;
+        self.fl.n = false;
+        self.fl.cy = ans > 0xffff;
+        self.set_pair(0x20, ans);
+    }
+
+    fn adc_hl(&mut self, op: u8) {
+        let (hl, rr, carry) = (self.hl(), self.get_long(op), usize::from(self.fl.cy));
+        let ans = hl + rr + carry;
+        let res = ans & 0xffff;
+        self.fl.s = res & 0x8000 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = (hl & 0xfff) + (rr & 0xfff) + carry > 0xfff;
+        self.fl.p = (hl ^ res) & (rr ^ res) & 0x8000 != 0;
+        self.fl.n = false;
+        self.fl.cy = ans > 0xffff;
+        self.set_pair(0x20, res);
+    }
+
+    fn sbc_hl(&mut self, op: u8) {
+        let (hl, rr, borrow) = (self.hl(), self.get_long(op), usize::from(self.fl.cy));
+        let res = hl.wrapping_sub(rr).wrapping_sub(borrow) & 0xffff;
+        self.fl.s = res & 0x8000 != 0;
+        self.fl.z = res == 0;
+        self.fl.ac = hl & 0xfff < (rr & 0xfff) + borrow;
+        self.fl.p = (hl ^ rr) & (hl ^ res) & 0x8000 != 0;
+        self.fl.n = true;
+        self.fl.cy = hl < rr + borrow;
+        self.set_pair(0x20, res);
+    }
+
+    /// RLCA, RRCA, RLA and RRA, which also clear H and N.
+    fn rotate_a(&mut self, op: u8) {
+        match op {
+            0x07 => self.rlc(op),
+            0x0f => self.rrc(op),
+            0x17 => self.ral(op),
+            _ => self.rar(op),
+        }
+        self.fl.ac = false;
+        self.fl.n = false;
+    }
+
+    /// DAA, which also corrects the result of subtractions.
+    fn daa_z80(&mut self, _op: u8) {
+        let a = self.a;
+        let mut correction = 0;
+        let mut cy = self.fl.cy;
+        if self.fl.ac || a & 0xf > 9 {
+            correction |= 0x06;
+        }
+        if cy || a > 0x99 {
+            correction |= 0x60;
+            cy = true;
+        }
+        let res = if self.fl.n {
+            self.fl.ac = self.fl.ac && a & 0xf < 6;
+            a.wrapping_sub(correction)
+        } else {
+            self.fl.ac = a & 0xf > 9;
+            a.wrapping_add(correction)
+        };
+        self.a = res;
+        self.set_r(res);
+        self.fl.cy = cy;
+    }
+
+    fn cpl(&mut self, _op: u8) {
+        self.a = !self.a;
+        self.fl.ac = true;
+        self.fl.n = true;
+    }
+
+    fn scf(&mut self, _op: u8) {
+        self.fl.cy = true;
+        self.fl.ac = false;
+        self.fl.n = false;
+    }
+
+    fn ccf(&mut self, _op: u8) {
+        self.fl.ac = self.fl.cy;
+        self.fl.cy = !self.fl.cy;
+        self.fl.n = false;
+    }
+
+    fn ex_af(&mut self, _op: u8) {
+        let af = (u16::from(self.a) << 8) | u16::from(self.fl.to_psw_z80());
+        let alt = self.z80.af_alt;
+        self.a = (alt >> 8) as u8;
+        self.fl = Flags::from_psw_z80(alt as u8);
+        self.state.z80.af_alt = af;
+    }
+
+    fn exx(&mut self, _op: u8) {
+        for &op in &[0x00, 0x10, 0x20] {
+            let val = self.get_long(op);
+            let alt = match op {
+                0x00 => &mut self.state.z80.bc_alt,
+                0x10 => &mut self.state.z80.de_alt,
+                _ => &mut self.state.z80.hl_alt,
+            };
+            let swapped = std::mem::replace(alt, val as u16);
+            self.set_pair(op, usize::from(swapped));
+        }
+    }
+
+    fn jump_relative(&mut self, offset: u8) {
+        self.pc = (self.pc as u16).wrapping_add(offset as i8 as u16).into();
+    }
+
+    fn djnz(&mut self, _op: u8) {
+        let offset = self.fetch();
+        self.b = self.b.wrapping_sub(1);
+        if self.b != 0 {
+            self.jump_relative(offset);
+        }
+    }
+
+    /// JR and JR cc.
+    fn jr(&mut self, op: u8) {
+        let offset = self.fetch();
+        if op == 0x18 || self.z80_condition(op) {
+            self.jump_relative(offset);
+        }
+    }
+
+    fn di_z80(&mut self, op: u8) {
+        self.di(op);
+        self.state.z80.iff2 = false;
+    }
+
+    fn ei_z80(&mut self, op: u8) {
+        self.ei(op);
+        self.state.z80.iff2 = true;
+    }
+
+    /// Rotations and shifts of the CB prefixed instructions.
+    fn shift(&mut self, kind: u8, val: u8) -> u8 {
+        let cy = u8::from(self.fl.cy);
+        let (res, out) = match kind {
+            0 => (val.rotate_left(1), val >> 7),       // RLC
+            1 => (val.rotate_right(1), val & 1),       // RRC
+            2 => ((val << 1) | cy, val >> 7),          // RL
+            3 => ((val >> 1) | (cy << 7), val & 1),    // RR
+            4 => (val << 1, val >> 7),                 // SLA
+            5 => ((val >> 1) | (val & 0x80), val & 1), // SRA
+            6 => ((val << 1) | 1, val >> 7),           // SLL, undocumented
+            _ => (val >> 1, val & 1),                  // SRL
+        };
+        self.set_r(res);
+        self.fl.ac = false;
+        self.fl.n = false;
+        self.fl.cy = out != 0;
+        res
+    }
+
+    /// Applies the CB prefixed `op` to `val`. Returns the result to write
+    /// back, except for BIT.
+    fn bit_op(&mut self, op: u8, val: u8) -> Option<u8> {
+        let bit = (op >> 3) & 7;
+        match op >> 6 {
+            0 => Some(self.shift(bit, val)),
+            1 => {
+                // BIT
+                let set = val & (1 << bit) != 0;
+                self.fl.z = !set;
+                self.fl.p = !set;
+                self.fl.s = bit == 7 && set;
+                self.fl.ac = true;
+                self.fl.n = false;
+                None
+            }
+            2 => Some(val & !(1 << bit)), // RES
+            _ => Some(val | (1 << bit)),  // SET
+        }
+    }
+
+    fn cb_prefix(&mut self) -> u8 {
+        let op = self.fetch_opcode();
+        let reg = op & 7;
+        let val = self.get_register(reg);
+        match (self.bit_op(op, val), reg) {
+            (Some(res), 6) => {
+                self.set_register(reg, res);
+                15
+            }
+            (Some(res), _) => {
+                self.set_register(reg, res);
+                8
+            }
+            (None, 6) => 12,
+            (None, _) => 8,
+        }
+    }
+
+    /// Instructions prefixed with DD or FD, which use IX or IY instead of
+    /// HL. The prefix is ignored by instructions that do not use HL.
+    fn index_prefix(&mut self, index: Index) -> u8 {
+        let op = self.fetch_opcode();
+        match op {
+            0xcb => self.index_cb(index),
+            // Only the last of several prefixes is applied
+            0xdd => 4 + self.index_prefix(Index::IX),
+            0xfd => 4 + self.index_prefix(Index::IY),
+            0xed => 4 + self.ed_prefix(),
+            // Not affected by the prefix
+            0xeb | 0xd9 => 4 + self.unprefixed(op),
+            _ if uses_memory_at_hl(op) => self.index_memory(index, op),
+            _ => {
+                self.swap_index(index);
+                let cycles = self.unprefixed(op);
+                self.swap_index(index);
+                4 + cycles
+            }
+        }
+    }
+
+    /// Fetches the displacement following a DD or FD prefix and returns the
+    /// address it points to.
+    fn indexed_addr(&mut self, index: Index) -> usize {
+        let offset = self.fetch() as i8;
+        usize::from(self.index(index).wrapping_add(offset as u16))
+    }
+
+    fn index_memory(&mut self, index: Index, op: u8) -> u8 {
+        let addr = self.indexed_addr(index);
+        match op {
+            0x34 | 0x35 => {
+                let val = self.read_byte(addr);
+                let res = if op == 0x34 {
+                    self.inc8(val)
+                } else {
+                    self.dec8(val)
+                };
+                self.write_byte(addr, res);
+                23
+            }
+            0x36 => {
+                let val = self.fetch();
+                self.write_byte(addr, val);
+                19
+            }
+            0x70..=0x77 => {
+                let val = self.get_register(op & 7);
+                self.write_byte(addr, val);
+                19
+            }
+            0x40..=0x7f => {
+                let val = self.read_byte(addr);
+                self.set_register((op >> 3) & 7, val);
+                19
+            }
+            _ => {
+                let val = self.read_byte(addr);
+                self.alu8(op, val);
+                19
+            }
+        }
+    }
+
+    /// DD CB and FD CB, whose opcode follows the displacement.
+    fn index_cb(&mut self, index: Index) -> u8 {
+        let addr = self.indexed_addr(index);
+        let op = self.fetch();
+        let val = self.read_byte(addr);
+        match self.bit_op(op, val) {
+            Some(res) => {
+                self.write_byte(addr, res);
+                // Undocumented: the result is also copied to a register
+                if op & 7 != 6 {
+                    self.set_register(op & 7, res);
+                }
+                23
+            }
+            None => 20,
+        }
+    }
+
+    fn ed_prefix(&mut self) -> u8 {
+        let op = self.fetch_opcode();
+        match op {
+            0x40..=0x7f => self.ed_misc(op),
+            0xa0..=0xa3 | 0xa8..=0xab | 0xb0..=0xb3 | 0xb8..=0xbb => self.block(op),
+            // Undefined, executed as two NOPs
+            _ => 8,
+        }
+    }
+
+    fn ed_misc(&mut self, op: u8) -> u8 {
+        let reg = (op >> 3) & 7;
+        match op & 7 {
+            0 => {
+                // IN r,(C). IN (C) only sets the flags.
+                let (port, cycles) = (self.c, self.cycles);
+                let val = self.io.read(port, cycles);
+                self.set_r(val);
+                self.fl.ac = false;
+                self.fl.n = false;
+                if reg != 6 {
+                    self.set_register(reg, val);
+                }
+                12
+            }
+            1 => {
+                // OUT (C),r. OUT (C),0 for the (HL) encoding.
+                let val = if reg == 6 { 0 } else { self.get_register(reg) };
+                let (port, cycles) = (self.c, self.cycles);
+                self.io.write(port, val, cycles);
+                12
+            }
+            2 => {
+                if op & 0x08 == 0 {
+                    self.sbc_hl(op);
+                } else {
+                    self.adc_hl(op);
+                }
+                15
+            }
+            3 => {
+                let addr = usize::from(self.fetch_word());
+                if op & 0x08 == 0 {
+                    // LD (nn),rr
+                    let val = self.get_long(op);
+                    self.write_byte(addr, val as u8);
+                    self.write_byte(addr + 1, (val >> 8) as u8);
+                } else {
+                    // LD rr,(nn)
+                    let val = self.word_at(addr);
+                    self.set_pair(op, usize::from(val));
+                }
+                20
+            }
+            4 => {
+                // NEG
+                let a = self.a;
+                self.a = self.sub8(0, a, false);
+                8
+            }
+            5 => {
+                // RETN, RETI
+                self.int_enable = self.z80.iff2;
+                self.ret();
+                14
+            }
+            6 => {
+                self.state.z80.im = match reg & 3 {
+                    0 | 1 => 0,
+                    2 => 1,
+                    _ => 2,
+                };
+                8
+            }
+            _ => match op {
+                0x47 => {
+                    self.state.z80.i = self.a;
+                    9
+                }
+                0x4f => {
+                    self.state.z80.r = self.a;
+                    9
+                }
+                0x57 | 0x5f => {
+                    // LD A,I and LD A,R
+                    let val = if op == 0x57 { self.z80.i } else { self.z80.r };
+                    self.a = val;
+                    self.fl.s = val & 0x80 != 0;
+                    self.fl.z = val == 0;
+                    self.fl.ac = false;
+                    self.fl.p = self.z80.iff2;
+                    self.fl.n = false;
+                    9
+                }
+                0x67 | 0x6f => {
+                    self.rotate_digit(op == 0x6f);
+                    18
+                }
+                _ => 8,
+            },
+        }
+    }
+
+    /// RLD and RRD, rotating the low digit of A and both digits at HL.
+    fn rotate_digit(&mut self, left: bool) {
+        let addr = self.hl();
+        let (mem, a) = (self.read_byte(addr), self.a);
+        let (mem, a) = if left {
+            ((mem << 4) | (a & 0x0f), (a & 0xf0) | (mem >> 4))
+        } else {
+            ((a << 4) | (mem >> 4), (a & 0xf0) | (mem & 0x0f))
+        };
+        self.write_byte(addr, mem);
+        self.a = a;
+        self.set_r(a);
+        self.fl.ac = false;
+        self.fl.n = false;
+    }
+
+    /// The block transfer, search and I/O instructions. The repeating forms
+    /// execute one iteration each time, rewinding the program counter until
+    /// they are done.
+    fn block(&mut self, op: u8) -> u8 {
+        let step = |addr: usize| {
+            if op & 0x08 == 0 {
+                (addr + 1) & 0xffff
+            } else {
+                addr.wrapping_sub(1) & 0xffff
+            }
+        };
+        let hl = self.hl();
+        let cycles = self.cycles;
+        let again = match op & 3 {
+            0 => {
+                // LDI, LDD
+                let val = self.read_byte(hl);
+                let de = self.de();
+                self.write_byte(de, val);
+                self.set_pair(0x10, step(de));
+                let bc = self.bc().wrapping_sub(1) & 0xffff;
+                self.set_pair(0x00, bc);
+                self.fl.ac = false;
+                self.fl.n = false;
+                self.fl.p = bc != 0;
+                bc != 0
+            }
+            1 => {
+                // CPI, CPD
+                let (val, a, cy) = (self.read_byte(hl), self.a, self.fl.cy);
+                let res = self.sub8(a, val, false);
+                let bc = self.bc().wrapping_sub(1) & 0xffff;
+                self.set_pair(0x00, bc);
+                self.fl.p = bc != 0;
+                self.fl.cy = cy;
+                bc != 0 && res != 0
+            }
+            2 => {
+                // INI, IND
+                let port = self.c;
+                let val = self.io.read(port, cycles);
+                self.write_byte(hl, val);
+                self.b = self.b.wrapping_sub(1);
+                self.fl.z = self.b == 0;
+                self.fl.n = true;
+                self.b != 0
+            }
+            _ => {
+                // OUTI, OUTD
+                let val = self.read_byte(hl);
+                self.b = self.b.wrapping_sub(1);
+                let port = self.c;
+                self.io.write(port, val, cycles);
+                self.fl.z = self.b == 0;
+                self.fl.n = true;
+                self.b != 0
+            }
+        };
+        self.set_pair(0x20, step(hl));
+        if op & 0x10 != 0 && again {
+            self.pc = self.pc.wrapping_sub(2) & 0xffff;
+            21
+        } else {
+            16
+        }
+    }
+
+    const fn handler_z80(op: u8) -> Instruction<T, M, I> {
+        match op {
+            0x08 => Self::ex_af,
+            0x10 => Self::djnz,
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Self::jr,
+            _ if op & 0xc6 == 0x04 => Self::inc_dec,
+            _ if op & 0xcf == 0x09 => Self::add_hl,
+            0x07 | 0x0f | 0x17 | 0x1f => Self::rotate_a,
+            0x27 => Self::daa_z80,
+            0x2f => Self::cpl,
+            0x37 => Self::scf,
+            0x3f => Self::ccf,
+            0x80..=0xbf => Self::alu_z80,
+            _ if op & 0xc7 == 0xc6 => Self::immediate_z80,
+            0xd9 => Self::exx,
+            0xf3 => Self::di_z80,
+            0xfb => Self::ei_z80,
+            // Decoded by `dispatch_z80`
+            0xcb | 0xdd | 0xed | 0xfd => Self::nop,
+            _ => Self::handler(op),
+        }
+    }
+
+    const HANDLERS_Z80: [Instruction<T, M, I>; 256] = {
+        let mut table = [Self::handler(0); 256];
+        let mut i = 0;
+        while i < 256 {
+            table[i] = Self::handler_z80(i as u8);
+            i += 1;
+        }
+        table
+    };
+}
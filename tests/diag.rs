@@ -1,41 +1,76 @@
-use emulator::{DefaultHandler, Emu8080};
+use emulator::cpm::{CpmExit, CpmMachine};
+use emulator::state::Model;
+use std::fs;
+use std::path::Path;
 
-pub static DIAG_BYTES: &'static [u8] = include_bytes!("cpudiag.bin");
+pub static DIAG_BYTES: &[u8] = include_bytes!("cpudiag.bin");
+
+/// Runs a CP/M test program, failing if it does not complete with `success`
+/// on its console.
+fn run_exerciser(machine: &mut CpmMachine, max_cycles: u64, success: &str) {
+    let exit = machine.run(max_cycles).unwrap();
+    assert_eq!(exit, CpmExit::WarmBoot, "Test program did not exit");
+    assert!(machine.output().contains(success));
+}
+
+/// Same as `run_exerciser`, also checking each of the tests the program
+/// reports.
+fn run_sub_tests(machine: &mut CpmMachine, max_cycles: u64, success: &str) {
+    run_exerciser(machine, max_cycles, success);
+    let tests = machine.sub_tests();
+    assert!(!tests.is_empty(), "No test results in the output");
+    for test in tests {
+        assert!(test.passed, "{}: {}", test.name, test.result);
+    }
+}
+
+fn load_com(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(name);
+    fs::read(&path).unwrap_or_else(|err| panic!("Could not read {}: {}", path.display(), err))
+}
 
 #[test]
 pub fn run_diag() {
-    let mut emu = Emu8080::new(DefaultHandler);
-    emu.memory[0x100..(0x100 + DIAG_BYTES.len())].copy_from_slice(DIAG_BYTES);
-    emu.memory[368] = 0x7;
-    //Skip DAA test
-    emu.memory[0x59c] = 0xc3; //JMP
-    emu.memory[0x59d] = 0xc2;
-    emu.memory[0x59e] = 0x05;
-
-    emu.pc = 0x100;
-    while emu.step_dis() == 0 {
-        if emu.pc == 0x0689 {
-            // Error procedure
-            eprintln!("\x1b[1;31mDiagnostic failed\x1b[0m");
-            return;
-        } else if emu.pc == 0x069B {
-            // Success procedure
-            eprintln!("\x1b[1;32mDiagnostic successful\x1b[0m");
-            return;
-        } else if emu.pc == 5 {
-            // Print routine
-            if emu.c == 9 {
-                let mut s = emu.de();
-                while emu.memory[s] != b'$' {
-                    print!("{}", emu.memory[s] as char);
-                    s += 1;
-                }
-                println!();
-            }
-            emu.ret();
-        } else if emu.pc == 0 {
-            // End of program
-            return;
-        }
-    }
+    let mut machine = CpmMachine::new(DIAG_BYTES);
+    run_exerciser(&mut machine, 10_000_000, "CPU IS OPERATIONAL");
+}
+
+// The exercisers below are not distributed with the crate. Copy their .COM
+// images into tests/ and run `cargo test --release --features exercisers`.
+
+#[test]
+#[cfg_attr(not(feature = "exercisers"), ignore = "requires tests/8080PRE.COM")]
+pub fn run_8080pre() {
+    let mut machine = CpmMachine::new(&load_com("8080PRE.COM"));
+    run_exerciser(&mut machine, 100_000_000, "8080 Preliminary tests complete");
+}
+
+#[test]
+#[cfg_attr(not(feature = "exercisers"), ignore = "requires tests/TST8080.COM")]
+pub fn run_tst8080() {
+    let mut machine = CpmMachine::new(&load_com("TST8080.COM"));
+    run_exerciser(&mut machine, 100_000_000, "CPU IS OPERATIONAL");
+}
+
+#[test]
+#[cfg_attr(not(feature = "exercisers"), ignore = "requires tests/CPUTEST.COM")]
+pub fn run_cputest() {
+    let mut machine = CpmMachine::new(&load_com("CPUTEST.COM"));
+    run_exerciser(&mut machine, 1_000_000_000, "CPU TESTS OK");
+}
+
+#[test]
+#[cfg_attr(not(feature = "exercisers"), ignore = "requires tests/8080EXM.COM")]
+pub fn run_8080exm() {
+    let mut machine = CpmMachine::new(&load_com("8080EXM.COM"));
+    run_sub_tests(&mut machine, 50_000_000_000, "Tests complete");
+}
+
+#[test]
+#[cfg_attr(not(feature = "exercisers"), ignore = "requires tests/ZEXDOC.COM")]
+pub fn run_zexdoc() {
+    let mut machine = CpmMachine::with_model(&load_com("ZEXDOC.COM"), Model::Z80);
+    run_sub_tests(&mut machine, 50_000_000_000, "Tests complete");
 }
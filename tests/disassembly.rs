@@ -0,0 +1,38 @@
+use emulator::asm::assemble;
+use emulator::dis::{Disassembly, Format, Hex};
+use emulator::symbols::Symbols;
+
+static INVADERS_ROM: &[u8] = include_bytes!("../src/invaders.rom");
+
+const ENTRIES: [u16; 3] = [0x00, 0x08, 0x10];
+
+fn reassemble(dis: &Disassembly) -> Vec<u8> {
+    let listing = dis.to_string();
+    match assemble(&listing) {
+        Ok(program) => program.binary(),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn listing_reassembles() {
+    let dis = Disassembly::new(INVADERS_ROM, 0, &ENTRIES);
+    assert_eq!(reassemble(&dis), INVADERS_ROM);
+}
+
+#[test]
+fn formatted_listing_reassembles() {
+    let mut symbols =
+        Symbols::parse("0008 MidScreen\n18D4 Init\n20C0 IsrDelay ; Counts down each interrupt")
+            .unwrap();
+    symbols.set_comment(0x0010, "End of screen");
+    let mut dis = Disassembly::with_symbols(INVADERS_ROM, 0, &ENTRIES, &symbols);
+    for &hex in &[Hex::Dollar, Hex::Prefix] {
+        dis.format = Format {
+            hex,
+            lowercase: true,
+            ..Format::default()
+        };
+        assert_eq!(reassemble(&dis), INVADERS_ROM);
+    }
+}